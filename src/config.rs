@@ -0,0 +1,264 @@
+//! Declarative configuration of a `MockServer`, loaded from a YAML (`yaml`
+//! feature) or TOML (`toml` feature) file by `MockServer::from_config_file`.
+//!
+//! Each key maps to the builder method of the same name. Durations are
+//! written like `100ms`, `2s` or `1m 30s`. Every key is optional:
+//!
+//! ```yaml
+//! host: 127.0.0.1
+//! port: 9000
+//! # answered in order to the text messages of each connection
+//! responses:
+//!   - { "status": "pending" }
+//!   - { "status": "done" }
+//! # connections whose handshake query has token=admin get this response
+//! when_query:
+//!   - key: token
+//!     value: admin
+//!     response: { "role": "admin" }
+//! # messages with a token field get this response, whatever its value
+//! when_has_path:
+//!   - pointer: /token
+//!     response: { "authenticated": true }
+//! response_headers:
+//!   X-Server: surimi
+//! health_check: true
+//! accept_delay: 100ms
+//! pong_delay: 50ms
+//! close_delay: 1s
+//! global_idle_timeout: 30s
+//! lifetime: 5m
+//! # once responses are exhausted: send_text, close (with a close code),
+//! # silent or echo
+//! on_exhausted:
+//!   close: 1000
+//! ```
+//!
+//! The same configuration in TOML:
+//!
+//! ```toml
+//! host = "127.0.0.1"
+//! port = 9000
+//! responses = [{ status = "pending" }, { status = "done" }]
+//! health_check = true
+//! accept_delay = "100ms"
+//! close_delay = "1s"
+//! on_exhausted = { close = 1000 }
+//!
+//! [[when_query]]
+//! key = "token"
+//! value = "admin"
+//! response = { role = "admin" }
+//!
+//! [response_headers]
+//! X-Server = "surimi"
+//! ```
+use crate::error::ConfigError;
+use crate::response::ExhaustBehavior;
+use crate::MockServer;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Format of a configuration file, given by its extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Yaml,
+    Toml,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default)]
+    responses: Vec<Value>,
+    #[serde(default)]
+    when_query: Vec<QueryRule>,
+    #[serde(default)]
+    when_has_path: Vec<PathRule>,
+    #[serde(default)]
+    response_headers: BTreeMap<String, String>,
+    health_check: Option<bool>,
+    accept_delay: Option<String>,
+    pong_delay: Option<String>,
+    close_delay: Option<String>,
+    global_idle_timeout: Option<String>,
+    lifetime: Option<String>,
+    on_exhausted: Option<OnExhausted>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryRule {
+    key: String,
+    value: String,
+    response: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathRule {
+    pointer: String,
+    response: Value,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OnExhausted {
+    SendText(String),
+    Close(u16),
+    Silent,
+    Echo,
+}
+
+impl MockServer {
+    /// Build a server from a declarative configuration file, see the
+    /// `config` module for its schema. The format is given by the file
+    /// extension: `.yaml` or `.yml` with the `yaml` feature, `.toml` with
+    /// the `toml` feature.
+    /// Returns a `ConfigError::InvalidConfigFile` if the file cannot be read
+    /// or parsed, e.g. because it uses an unknown key, and a
+    /// `ConfigError::InvalidValue` if a value is invalid.
+    pub fn from_config_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let invalid = |error: String| ConfigError::InvalidConfigFile {
+            path: path.display().to_string(),
+            error,
+        };
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => return Err(invalid("unsupported file extension".into())),
+        };
+        let text = std::fs::read_to_string(path).map_err(|error| invalid(error.to_string()))?;
+        let config = parse(&text, format).map_err(invalid)?;
+        config.build()
+    }
+}
+
+fn parse(text: &str, format: Format) -> Result<Config, String> {
+    match format {
+        #[cfg(feature = "yaml")]
+        Format::Yaml => serde_yaml::from_str(text).map_err(|error| error.to_string()),
+        #[cfg(feature = "toml")]
+        Format::Toml => toml::from_str(text).map_err(|error| error.to_string()),
+        #[cfg(not(feature = "yaml"))]
+        Format::Yaml => Err("YAML config files require the `yaml` feature".into()),
+        #[cfg(not(feature = "toml"))]
+        Format::Toml => Err("TOML config files require the `toml` feature".into()),
+    }
+}
+
+fn duration(option: &'static str, value: &Option<String>) -> Result<Option<Duration>, ConfigError> {
+    value
+        .as_deref()
+        .map(|value| {
+            humantime::parse_duration(value).map_err(|error| ConfigError::InvalidValue {
+                option,
+                reason: error.to_string(),
+            })
+        })
+        .transpose()
+}
+
+impl Config {
+    fn build(self) -> Result<MockServer, ConfigError> {
+        let mut server = MockServer::default().responses(self.responses);
+        if let Some(host) = self.host {
+            server = server.host(host);
+        }
+        if let Some(port) = self.port {
+            server = server.port(port);
+        }
+        for rule in self.when_query {
+            server = server.when_query(rule.key, rule.value, rule.response);
+        }
+        for rule in self.when_has_path {
+            server = server.when_has_path(&rule.pointer, rule.response);
+        }
+        if !self.response_headers.is_empty() {
+            server = server.response_headers(self.response_headers.into_iter().collect());
+        }
+        if let Some(enabled) = self.health_check {
+            server = server.health_check(enabled);
+        }
+        if let Some(delay) = duration("accept_delay", &self.accept_delay)? {
+            server = server.accept_delay(delay);
+        }
+        if let Some(delay) = duration("pong_delay", &self.pong_delay)? {
+            server = server.pong_delay(delay);
+        }
+        if let Some(delay) = duration("close_delay", &self.close_delay)? {
+            server = server.close_delay(delay);
+        }
+        if let Some(timeout) = duration("global_idle_timeout", &self.global_idle_timeout)? {
+            server = server.global_idle_timeout(timeout);
+        }
+        if let Some(lifetime) = duration("lifetime", &self.lifetime)? {
+            server = server.lifetime(lifetime);
+        }
+        if let Some(behavior) = self.on_exhausted {
+            server = server.on_exhausted(match behavior {
+                OnExhausted::SendText(text) => ExhaustBehavior::SendText(text),
+                OnExhausted::Close(code) => ExhaustBehavior::Close(code.into()),
+                OnExhausted::Silent => ExhaustBehavior::Silent,
+                OnExhausted::Echo => ExhaustBehavior::Echo,
+            });
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn build_server_from_yaml() {
+        use async_tungstenite::tungstenite::Message;
+        use serde_json::json;
+
+        let config = parse(
+            "port: 9000\nresponses:\n  - { \"id\": 1 }\nclose_delay: 1s\non_exhausted: silent\nwhen_has_path:\n  - { pointer: /token, response: true }\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        let server = config.build().unwrap();
+        assert_eq!(server.options.port, 9000);
+        assert_eq!(server.responses, vec![json!({"id": 1}).into()]);
+        assert_eq!(server.close_delay, Some(Duration::from_secs(1)));
+        assert_eq!(server.exhausted, ExhaustBehavior::Silent);
+        let request = Message::Text(json!({"token": "abc"}).to_string());
+        assert_eq!(server.matchers.len(), 1);
+        assert_eq!(
+            server.matchers[0].matches(&request),
+            Some(json!(true).into())
+        );
+
+        let error = parse("unknown_option: 1\n", Format::Yaml).unwrap_err();
+        assert!(error.contains("unknown field `unknown_option`"));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn build_server_from_toml() {
+        let config = parse(
+            "pong_delay = \"soon\"\n\n[[when_query]]\nkey = \"token\"\nvalue = \"admin\"\nresponse = { role = \"admin\" }\n",
+            Format::Toml,
+        )
+        .unwrap();
+        assert_eq!(config.when_query.len(), 1);
+        assert!(matches!(
+            config.build().err().unwrap(),
+            ConfigError::InvalidValue {
+                option: "pong_delay",
+                ..
+            }
+        ));
+    }
+}
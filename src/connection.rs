@@ -0,0 +1,344 @@
+use crate::frame::{self, Pacing};
+use crate::handle::{Direction, LatencyStats, State};
+use crate::stream::Transport;
+use crate::Socket;
+use async_std::channel::Receiver;
+use async_tungstenite::tungstenite::protocol::frame::Frame;
+use async_tungstenite::tungstenite::protocol::Message;
+use async_tungstenite::tungstenite::Error as WsError;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Connection is the message level stream a client is served on, so the
+/// serving loop does not depend on how the messages are transported.
+pub(crate) trait Connection:
+    Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin
+{
+    /// Write `frame` as is, bypassing the WebSocket codec so its header can
+    /// be crafted freely, paced as given.
+    async fn write_frame(&mut self, frame: Frame, pacing: Pacing) -> Result<(), Box<dyn Error>>;
+
+    /// Write `bytes` as is, bypassing the WebSocket codec, so they may not
+    /// even form a valid frame.
+    async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    /// Make the transport reset the connection instead of closing it
+    /// gracefully once dropped.
+    fn reset_on_close(&self) -> io::Result<()>;
+
+    /// Drop the pongs tungstenite automatically answers pings with, or send
+    /// them again.
+    fn drop_pongs(&mut self, dropped: bool);
+
+    /// Receiver of the opcodes of the reserved frames the transport drops,
+    /// if it filters them, the first time it is called.
+    fn take_reserved(&mut self) -> Option<Receiver<u8>>;
+
+    /// Whether the messages sent from now on answer the received ones, so
+    /// their latency is recorded, or are pushed independently of them.
+    fn answering(&mut self, answering: bool);
+}
+
+impl<S: Transport> Connection for Socket<S> {
+    async fn write_frame(&mut self, frame: Frame, pacing: Pacing) -> Result<(), Box<dyn Error>> {
+        match pacing {
+            Pacing::Immediate => frame::write(self, frame).await,
+            Pacing::Throttled(bytes_per_sec) => {
+                frame::write_throttled(self, frame, bytes_per_sec).await
+            }
+            Pacing::Stalled { after, stall } => {
+                frame::write_stalled(self, frame, after, stall).await
+            }
+        }
+    }
+
+    async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        frame::write_raw(self, bytes).await
+    }
+
+    fn reset_on_close(&self) -> io::Result<()> {
+        self.get_ref().get_ref().reset_on_close()
+    }
+
+    fn drop_pongs(&mut self, dropped: bool) {
+        self.get_mut().drop_pongs(dropped);
+    }
+
+    fn take_reserved(&mut self) -> Option<Receiver<u8>> {
+        self.get_mut().take_reserved()
+    }
+
+    fn answering(&mut self, _: bool) {}
+}
+
+/// Traffic of a connection: the bytes written and read on its transport,
+/// counted by `Replay`, and the text and binary messages sent and received
+/// along with the response latencies, recorded by `Tracked`.
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) messages_sent: AtomicUsize,
+    pub(crate) messages_received: AtomicUsize,
+    pub(crate) response_latencies: Latencies,
+}
+
+/// Latencies recorded as nanoseconds in atomics, so recording them neither
+/// locks nor allocates, summarized by `LatencyStats`. As the atomics are
+/// updated one after the other, a summary taken while a latency is being
+/// recorded is approximate.
+#[derive(Debug)]
+pub(crate) struct Latencies {
+    count: AtomicUsize,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Latencies {
+    fn default() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            total: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Latencies {
+    pub(crate) fn record(&self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX.into()) as u64;
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.total.fetch_add(nanos, Ordering::SeqCst);
+        self.min.fetch_min(nanos, Ordering::SeqCst);
+        self.max.fetch_max(nanos, Ordering::SeqCst);
+    }
+
+    /// Summary of the latencies recorded so far.
+    pub(crate) fn stats(&self) -> LatencyStats {
+        let count = self.count.load(Ordering::SeqCst);
+        if count == 0 {
+            return LatencyStats::default();
+        }
+        let total = self.total.load(Ordering::SeqCst);
+        let max = self.max.load(Ordering::SeqCst);
+        // the first latency may be counted before it is the minimum
+        let min = self.min.load(Ordering::SeqCst).min(max);
+        LatencyStats {
+            count,
+            min: Duration::from_nanos(min),
+            max: Duration::from_nanos(max),
+            mean: Duration::from_nanos(total / count as u64),
+        }
+    }
+}
+
+/// Tracked wraps a connection to count the messages sent and received on it
+/// in its `Traffic`, and to record the messages sent on it in the
+/// transcript of the server. Responses written as raw frames are recorded
+/// by the server, which knows their uncompressed message.
+/// The latency of a response is the time since the first message received
+/// after the previous response, recorded for the connection and the server.
+/// Only the messages sent while `answering` count as responses, not the
+/// ones pushed independently of the received messages.
+pub(crate) struct Tracked<'a, C> {
+    inner: C,
+    traffic: Arc<Traffic>,
+    state: &'a State,
+    connection: usize,
+    received_at: Option<Instant>,
+    answering: bool,
+}
+
+impl<'a, C> Tracked<'a, C> {
+    pub(crate) fn new(
+        inner: C,
+        traffic: Arc<Traffic>,
+        state: &'a State,
+        connection: usize,
+    ) -> Self {
+        Self {
+            inner,
+            traffic,
+            state,
+            connection,
+            received_at: None,
+            answering: false,
+        }
+    }
+
+    /// Record the latency of a message sent in response to the received ones.
+    fn record_latency(&mut self) {
+        if !self.answering {
+            return;
+        }
+        if let Some(received_at) = self.received_at.take() {
+            let latency = received_at.elapsed();
+            self.traffic.response_latencies.record(latency);
+            self.state.response_latencies.record(latency);
+        }
+    }
+}
+
+impl<C: Connection> Stream for Tracked<'_, C> {
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(message))) = &poll {
+            if message.is_text() || message.is_binary() {
+                self.traffic
+                    .messages_received
+                    .fetch_add(1, Ordering::SeqCst);
+                self.received_at.get_or_insert_with(Instant::now);
+            }
+        }
+        poll
+    }
+}
+
+impl<C: Connection> Sink<Message> for Tracked<'_, C> {
+    type Error = WsError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), WsError> {
+        if message.is_text() || message.is_binary() {
+            self.traffic.messages_sent.fetch_add(1, Ordering::SeqCst);
+            self.record_latency();
+            self.state
+                .record_transcript(self.connection, Direction::Sent, &message);
+        }
+        Pin::new(&mut self.inner).start_send(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<C: Connection> Connection for Tracked<'_, C> {
+    async fn write_frame(&mut self, frame: Frame, pacing: Pacing) -> Result<(), Box<dyn Error>> {
+        // frames and raw bytes are only written directly to send responses
+        self.traffic.messages_sent.fetch_add(1, Ordering::SeqCst);
+        self.record_latency();
+        self.inner.write_frame(frame, pacing).await
+    }
+
+    async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.traffic.messages_sent.fetch_add(1, Ordering::SeqCst);
+        self.record_latency();
+        self.inner.write_raw(bytes).await
+    }
+
+    fn reset_on_close(&self) -> io::Result<()> {
+        self.inner.reset_on_close()
+    }
+
+    fn drop_pongs(&mut self, dropped: bool) {
+        self.inner.drop_pongs(dropped);
+    }
+
+    fn take_reserved(&mut self) -> Option<Receiver<u8>> {
+        self.inner.take_reserved()
+    }
+
+    fn answering(&mut self, answering: bool) {
+        self.answering = answering;
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockConnection;
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// In-memory connection for unit tests: it yields the `received`
+    /// messages then ends, and records what is sent on it.
+    #[derive(Default)]
+    pub(crate) struct MockConnection {
+        pub(crate) received: VecDeque<Message>,
+        pub(crate) sent: Vec<Message>,
+        pub(crate) frames: Vec<Frame>,
+        pub(crate) raw: Vec<Vec<u8>>,
+    }
+
+    impl MockConnection {
+        pub(crate) fn new(received: impl IntoIterator<Item = Message>) -> Self {
+            Self {
+                received: received.into_iter().collect(),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Stream for MockConnection {
+        type Item = Result<Message, WsError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.received.pop_front().map(Ok))
+        }
+    }
+
+    impl Sink<Message> for MockConnection {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), WsError> {
+            self.sent.push(message);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Connection for MockConnection {
+        async fn write_frame(&mut self, frame: Frame, _: Pacing) -> Result<(), Box<dyn Error>> {
+            self.frames.push(frame);
+            Ok(())
+        }
+
+        async fn write_raw(&mut self, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+            self.raw.push(bytes.to_vec());
+            Ok(())
+        }
+
+        fn reset_on_close(&self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn drop_pongs(&mut self, _: bool) {}
+
+        fn take_reserved(&mut self) -> Option<Receiver<u8>> {
+            None
+        }
+
+        fn answering(&mut self, _: bool) {}
+    }
+}
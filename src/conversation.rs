@@ -0,0 +1,63 @@
+use async_std::io::{Read, Write};
+use async_tungstenite::tungstenite::protocol::Message;
+use async_tungstenite::WebSocketStream;
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use serde_json::Value;
+use std::error::Error;
+
+/// Drive a scripted conversation from the client side: send each request of
+/// `conversation` on `stream` and assert the next message received is the
+/// expected response.
+/// It is the client half of `MockServer::expect_conversation`, and the
+/// recommended way to test a conversation end to end.
+///
+/// # Panics
+/// Panics if a response differs from the expected one.
+///
+/// # Examples
+/// ```
+/// use surimi::{run_conversation, MockServer};
+/// use serde_json::json;
+///
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let conversation = vec![
+///         (json!({"action": "login"}), json!({"status": "logged"})),
+///         (json!({"action": "subscribe"}), json!({"status": "subscribed"})),
+///     ];
+///     let server = MockServer::default()
+///         .expect_conversation(conversation.clone())
+///         .start()
+///         .await?;
+///
+///     let endpoint = format!("ws://{}:{}", server.host(), server.port());
+///     let (mut stream, _) = async_tungstenite::async_std::connect_async(endpoint).await?;
+///     run_conversation(&mut stream, &conversation).await?;
+///
+///     stream.close(None).await?;
+/// #   Ok(())
+/// # }
+/// ```
+pub async fn run_conversation<S>(
+    stream: &mut WebSocketStream<S>,
+    conversation: &[(Value, Value)],
+) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Unpin,
+{
+    for (index, (request, expected)) in conversation.iter().enumerate() {
+        stream.send(Message::Text(request.to_string())).await?;
+        let message = stream
+            .next()
+            .await
+            .ok_or("connection closed before the end of the conversation")??;
+        let response: Value = serde_json::from_str(&message.into_text()?)?;
+        assert_eq!(
+            &response, expected,
+            "unexpected response to request #{} {}",
+            index, request
+        );
+    }
+    Ok(())
+}
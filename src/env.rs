@@ -0,0 +1,85 @@
+use std::error::Error;
+use std::fmt;
+
+/// What to do with a `${VAR}` token whose variable is not set, see
+/// `MockServer::missing_env`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MissingEnv {
+    /// Leave the token as is.
+    #[default]
+    Keep,
+    /// Fail sending the response, which ends the connection with an error.
+    Fail,
+}
+
+/// A `${VAR}` token referenced a variable which is not set.
+#[derive(Debug)]
+pub(crate) struct MissingVariable(String);
+
+impl fmt::Display for MissingVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "environment variable {} is not set", self.0)
+    }
+}
+
+impl Error for MissingVariable {}
+
+/// Replace the `${VAR}` tokens of `text` with the value of the environment
+/// variable `VAR`. `$${` is an escaped, literal `${`.
+pub(crate) fn interpolate(text: &str, missing: MissingEnv) -> Result<String, MissingVariable> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            output.push_str("${");
+            rest = escaped;
+            continue;
+        }
+        let token = rest
+            .strip_prefix("${")
+            .and_then(|token| token.find('}').map(|end| &token[..end]))
+            .filter(|name| !name.is_empty());
+        match token {
+            Some(name) => {
+                match (std::env::var(name), missing) {
+                    (Ok(value), _) => output.push_str(&value),
+                    (Err(_), MissingEnv::Keep) => output.push_str(&rest[..name.len() + 3]),
+                    (Err(_), MissingEnv::Fail) => return Err(MissingVariable(name.into())),
+                }
+                rest = &rest[name.len() + 3..];
+            }
+            None => {
+                output.push('$');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_set_escaped_and_missing_variables() {
+        std::env::set_var("SURIMI_TEST_API_URL", "http://localhost");
+        assert_eq!(
+            interpolate(
+                "${SURIMI_TEST_API_URL}/v1 $${SURIMI_TEST_API_URL} ${SURIMI_TEST_UNSET} $5 ${",
+                MissingEnv::Keep
+            )
+            .unwrap(),
+            "http://localhost/v1 ${SURIMI_TEST_API_URL} ${SURIMI_TEST_UNSET} $5 ${"
+        );
+        assert_eq!(
+            interpolate("${SURIMI_TEST_UNSET}", MissingEnv::Fail)
+                .unwrap_err()
+                .to_string(),
+            "environment variable SURIMI_TEST_UNSET is not set"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Responses of common API envelope shapes, to queue them without writing
+//! the envelope of each one with `json!`.
+//!
+//! `Response` builds them with the default shapes:
+//!
+//! - `Response::ok(data)`: `{"status": "ok", "data": data}`
+//! - `Response::error(code, message)`:
+//!   `{"status": "error", "error": {"code": code, "message": message}}`
+//! - `Response::event(name, payload)`: `{"event": name, "payload": payload}`
+//!
+//! If an API uses other shapes, `Envelopes` builds the same responses from
+//! custom templates: JSON values where the `{data}`, `{code}`, `{message}`,
+//! `{name}` and `{payload}` placeholders are replaced. A string which is
+//! exactly a placeholder is replaced by its value, keeping its JSON type,
+//! while a placeholder inside a longer string is replaced by its text.
+//!
+//! # Examples
+//! ```
+//! use surimi::{Envelopes, MockServer, Response};
+//! use serde_json::json;
+//!
+//! # #[async_std::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let envelopes = Envelopes::default().ok_template(json!({"success": true, "result": "{data}"}));
+//! let server = MockServer::default()
+//!     .responses(vec![
+//!         Response::ok(json!({"id": 1})),
+//!         Response::error(500, "boom"),
+//!         envelopes.ok(json!({"id": 2})),
+//!     ])
+//!     .start()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::response::MockResponse;
+use serde_json::{json, Value};
+
+/// Response builds responses of the default envelope shapes, see the
+/// `envelope` module.
+pub struct Response;
+
+impl Response {
+    /// Successful response carrying `data`.
+    pub fn ok(data: Value) -> MockResponse {
+        Envelopes::default().ok(data)
+    }
+
+    /// Error response with an error `code` and `message`.
+    pub fn error(code: i64, message: &str) -> MockResponse {
+        Envelopes::default().error(code, message)
+    }
+
+    /// Event `name` pushed with its `payload`.
+    pub fn event(name: &str, payload: Value) -> MockResponse {
+        Envelopes::default().event(name, payload)
+    }
+}
+
+/// Envelopes builds responses from templates of envelope shapes, see the
+/// `envelope` module. The default templates are the ones of `Response`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelopes {
+    ok: Value,
+    error: Value,
+    event: Value,
+}
+
+impl Default for Envelopes {
+    fn default() -> Self {
+        Self {
+            ok: json!({"status": "ok", "data": "{data}"}),
+            error: json!({"status": "error", "error": {"code": "{code}", "message": "{message}"}}),
+            event: json!({"event": "{name}", "payload": "{payload}"}),
+        }
+    }
+}
+
+impl Envelopes {
+    /// Template of successful responses, with a `{data}` placeholder.
+    pub fn ok_template(mut self, template: Value) -> Self {
+        self.ok = template;
+        self
+    }
+
+    /// Template of error responses, with `{code}` and `{message}`
+    /// placeholders.
+    pub fn error_template(mut self, template: Value) -> Self {
+        self.error = template;
+        self
+    }
+
+    /// Template of events, with `{name}` and `{payload}` placeholders.
+    pub fn event_template(mut self, template: Value) -> Self {
+        self.event = template;
+        self
+    }
+
+    /// Successful response carrying `data`.
+    pub fn ok(&self, data: Value) -> MockResponse {
+        fill(&self.ok, &[("{data}", data)]).into()
+    }
+
+    /// Error response with an error `code` and `message`.
+    pub fn error(&self, code: i64, message: &str) -> MockResponse {
+        fill(
+            &self.error,
+            &[("{code}", code.into()), ("{message}", message.into())],
+        )
+        .into()
+    }
+
+    /// Event `name` pushed with its `payload`.
+    pub fn event(&self, name: &str, payload: Value) -> MockResponse {
+        fill(
+            &self.event,
+            &[("{name}", name.into()), ("{payload}", payload)],
+        )
+        .into()
+    }
+}
+
+/// Replace the `placeholders` of the strings of `template`.
+fn fill(template: &Value, placeholders: &[(&str, Value)]) -> Value {
+    match template {
+        Value::String(text) => {
+            if let Some((_, value)) = placeholders.iter().find(|(name, _)| name == text) {
+                return value.clone();
+            }
+            let mut text = text.clone();
+            for (name, value) in placeholders {
+                let replacement = match value {
+                    Value::String(string) => string.clone(),
+                    value => value.to_string(),
+                };
+                text = text.replace(name, &replacement);
+            }
+            Value::String(text)
+        }
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| fill(value, placeholders))
+                .collect(),
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), fill(value, placeholders)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_envelopes() {
+        assert_eq!(
+            Response::error(500, "boom"),
+            json!({"status": "error", "error": {"code": 500, "message": "boom"}}).into()
+        );
+        assert_eq!(
+            Response::event("tick", json!([1, 2])),
+            json!({"event": "tick", "payload": [1, 2]}).into()
+        );
+
+        let envelopes = Envelopes::default()
+            .error_template(json!({"ok": false, "reason": "{message} ({code})"}));
+        assert_eq!(
+            envelopes.error(404, "not found"),
+            json!({"ok": false, "reason": "not found (404)"}).into()
+        );
+        assert_eq!(
+            envelopes.ok(json!({"id": 1})),
+            json!({"status": "ok", "data": {"id": 1}}).into()
+        );
+    }
+}
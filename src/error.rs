@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::fmt;
+
+/// ConfigError is returned by `MockServer::start` when the server
+/// configuration is invalid.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// A response header would override a mandatory WebSocket handshake header.
+    ReservedHeader(String),
+    /// A response header name or value is not a valid HTTP header.
+    InvalidHeader(String),
+    /// A probability is not between 0 and 1.
+    InvalidProbability(f64),
+    /// The response at `index` of `MockServer::responses_str` is not valid JSON.
+    InvalidJson { index: usize, error: String },
+    /// The configuration file at `path` cannot be read or parsed, see
+    /// `MockServer::from_config_file`.
+    InvalidConfigFile { path: String, error: String },
+    /// A builder option was given an invalid value.
+    InvalidValue {
+        option: &'static str,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ReservedHeader(name) => write!(
+                f,
+                "header '{}' is mandatory for the WebSocket handshake and cannot be overridden",
+                name
+            ),
+            Self::InvalidHeader(name) => write!(f, "header '{}' is not a valid HTTP header", name),
+            Self::InvalidProbability(probability) => {
+                write!(f, "probability {} is not between 0 and 1", probability)
+            }
+            Self::InvalidJson { index, error } => {
+                write!(f, "response #{} is not valid JSON: {}", index, error)
+            }
+            Self::InvalidConfigFile { path, error } => {
+                write!(f, "invalid config file '{}': {}", path, error)
+            }
+            Self::InvalidValue { option, reason } => {
+                write!(f, "invalid value for '{}': {}", option, reason)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// SendError is returned by `ServerHandle::send` when a message cannot be sent.
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    /// No client is connected and the server rejects pending sends.
+    NoActiveConnection,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoActiveConnection => write!(f, "no client is connected to the server"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+/// WaitError is returned by `ServerHandle::wait_for` when the awaited
+/// message is not received.
+#[derive(Debug, PartialEq)]
+pub enum WaitError {
+    /// No matching message was received before the timeout.
+    Timeout,
+}
+
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "no matching message received before the timeout"),
+        }
+    }
+}
+
+impl Error for WaitError {}
@@ -0,0 +1,141 @@
+use crate::response::MockResponse;
+use crate::stream::Transport;
+use crate::Socket;
+use async_std::io::{self, WriteExt};
+use async_std::task;
+use async_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+use async_tungstenite::tungstenite::protocol::frame::Frame;
+use async_tungstenite::tungstenite::protocol::Message;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use futures_util::sink::SinkExt;
+use std::error::Error;
+use std::io::Write;
+use std::time::Duration;
+
+/// Trailer of a sync-flushed deflate block, removed from compressed
+/// payloads as required by RFC 7692.
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// How a frame written directly on the stream is paced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Pacing {
+    /// Written at once, see `write`.
+    Immediate,
+    /// Written at about the given bytes per second, see `write_throttled`.
+    Throttled(u64),
+    /// Written up to `after` bytes, then the rest once `stall` elapsed,
+    /// see `write_stalled`.
+    Stalled { after: usize, stall: Duration },
+}
+
+/// Build an uncompressed data frame carrying the response.
+pub(crate) fn uncompressed(response: &MockResponse) -> Frame {
+    let (data, opcode) = match response.to_message() {
+        Message::Binary(data) => (data, Data::Binary),
+        message => (message.into_data(), Data::Text),
+    };
+    Frame::message(data, OpCode::Data(opcode), true)
+}
+
+/// Build a data frame whose payload is compressed with permessage-deflate
+/// (RFC 7692): the payload is deflated and the RSV1 bit is set.
+pub(crate) fn compressed(response: &MockResponse) -> io::Result<Frame> {
+    let (data, opcode) = match response.to_message() {
+        Message::Binary(data) => (data, Data::Binary),
+        message => (message.into_data(), Data::Text),
+    };
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data)?;
+    encoder.flush()?;
+    let mut payload = std::mem::take(encoder.get_mut());
+    if payload.ends_with(&DEFLATE_TRAILER) {
+        payload.truncate(payload.len() - DEFLATE_TRAILER.len());
+    }
+
+    let mut frame = Frame::message(payload, OpCode::Data(opcode), true);
+    frame.header_mut().rsv1 = true;
+    Ok(frame)
+}
+
+/// Write `frame` directly on the underlying stream, bypassing tungstenite so
+/// its header can be crafted freely.
+/// Messages queued in tungstenite are flushed first to keep frames ordered.
+pub(crate) async fn write<S: Transport>(
+    socket: &mut Socket<S>,
+    frame: Frame,
+) -> Result<(), Box<dyn Error>> {
+    let bytes = flush_and_format(socket, frame).await?;
+    let stream = socket.get_mut();
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Write `bytes` as is on the underlying stream, whether they form a valid
+/// frame or not. Messages queued in tungstenite are flushed first.
+pub(crate) async fn write_raw<S: Transport>(
+    socket: &mut Socket<S>,
+    bytes: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    socket.flush().await?;
+    let stream = socket.get_mut();
+    stream.write_all(bytes).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Write `frame` like `write`, at about `bytes_per_sec`: the frame is
+/// written in chunks of a tenth of a second worth of bytes, sleeping after
+/// each chunk for the time it takes to transfer it at that rate.
+pub(crate) async fn write_throttled<S: Transport>(
+    socket: &mut Socket<S>,
+    frame: Frame,
+    bytes_per_sec: u64,
+) -> Result<(), Box<dyn Error>> {
+    let bytes = flush_and_format(socket, frame).await?;
+    let stream = socket.get_mut();
+    let chunk_size = (bytes_per_sec / 10).max(1) as usize;
+    for chunk in bytes.chunks(chunk_size) {
+        stream.write_all(chunk).await?;
+        stream.flush().await?;
+        task::sleep(Duration::from_secs_f64(
+            chunk.len() as f64 / bytes_per_sec as f64,
+        ))
+        .await;
+    }
+    Ok(())
+}
+
+/// Write `frame` like `write`, stalling for `stall` once `after` bytes of the
+/// frame (header included) were written, like a server whose send buffer
+/// is blocked.
+pub(crate) async fn write_stalled<S: Transport>(
+    socket: &mut Socket<S>,
+    frame: Frame,
+    after: usize,
+    stall: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let bytes = flush_and_format(socket, frame).await?;
+    let stream = socket.get_mut();
+    let (head, tail) = bytes.split_at(after.min(bytes.len()));
+    stream.write_all(head).await?;
+    stream.flush().await?;
+    if !tail.is_empty() {
+        task::sleep(stall).await;
+        stream.write_all(tail).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+async fn flush_and_format<S: Transport>(
+    socket: &mut Socket<S>,
+    frame: Frame,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    socket.flush().await?;
+    let mut bytes = Vec::with_capacity(frame.len());
+    frame.format(&mut bytes)?;
+    Ok(bytes)
+}
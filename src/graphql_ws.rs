@@ -0,0 +1,53 @@
+//! Envelopes of the GraphQL over WebSocket protocol (`graphql-transport-ws`
+//! subprotocol), enabled by the `graphql_ws` feature.
+//!
+//! # Examples
+//! ```
+//! use surimi::{MockResponse, MockServer};
+//! use serde_json::json;
+//!
+//! # #[async_std::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // answer connection_init with an ack, and the next messages with the
+//! // result of operation 1 then its completion
+//! let server = MockServer::default()
+//!     .response_headers(vec![(
+//!         "Sec-WebSocket-Protocol".into(),
+//!         "graphql-transport-ws".into(),
+//!     )])
+//!     .responses(vec![
+//!         MockResponse::graphql_ws_ack(),
+//!         MockResponse::graphql_ws_next("1", json!({"data": {"hello": "world"}})),
+//!         MockResponse::graphql_ws_complete("1"),
+//!     ])
+//!     .start()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::response::MockResponse;
+use serde_json::{json, Value};
+
+impl MockResponse {
+    /// `connection_ack` message, accepting the `connection_init` of the
+    /// client.
+    pub fn graphql_ws_ack() -> Self {
+        Self::Json(json!({"type": "connection_ack"}))
+    }
+
+    /// `next` message carrying the execution result `payload` of the
+    /// operation `id`.
+    pub fn graphql_ws_next(id: &str, payload: Value) -> Self {
+        Self::Json(json!({"id": id, "type": "next", "payload": payload}))
+    }
+
+    /// `complete` message, ending the operation `id`.
+    pub fn graphql_ws_complete(id: &str) -> Self {
+        Self::Json(json!({"id": id, "type": "complete"}))
+    }
+
+    /// `error` message, failing the operation `id` with GraphQL `errors`.
+    pub fn graphql_ws_error(id: &str, errors: Vec<Value>) -> Self {
+        Self::Json(json!({"id": id, "type": "error", "payload": errors}))
+    }
+}
@@ -0,0 +1,1210 @@
+use crate::connection::{Latencies, Traffic};
+use crate::error::{SendError, WaitError};
+use crate::response::MockResponse;
+use async_std::channel::{self, Receiver, Sender};
+use async_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{panic, thread};
+
+/// Query parameters sent by a client in its handshake request URI.
+/// A key can be sent several times, so each key maps to all its values
+/// in the order they were received.
+pub type QueryParams = HashMap<String, Vec<String>>;
+
+/// A text or binary message received by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedMessage {
+    /// Index of the connection the message was received on, in the order
+    /// connections were served (starting at 0).
+    pub connection: usize,
+    /// The received message.
+    pub message: Message,
+}
+
+/// Whether a message of the transcript was received or sent by the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Received from the client.
+    Received,
+    /// Sent to the client.
+    Sent,
+}
+
+/// A text or binary message received or sent by the server, see
+/// `MockServer::record_transcript`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptEntry {
+    /// Index of the connection the message was received or sent on.
+    pub connection: usize,
+    /// Whether the message was received or sent.
+    pub direction: Direction,
+    /// The message, uncompressed.
+    pub message: Message,
+    /// When the message was received or sent.
+    pub at: Instant,
+}
+
+/// A pong frame received by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedPong {
+    /// Index of the connection the pong was received on.
+    pub connection: usize,
+    /// Payload of the pong frame.
+    pub payload: Vec<u8>,
+    /// When the pong was received.
+    pub received_at: Instant,
+}
+
+/// A frame of a reserved opcode received by the server, see
+/// `MockServer::on_unsupported`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedFrame {
+    /// Index of the connection the frame was received on.
+    pub connection: usize,
+    /// Opcode of the frame.
+    pub opcode: u8,
+}
+
+/// What answered a text message received by the server, see
+/// `ServerHandle::match_log`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchedRule {
+    /// It subscribed to or unsubscribed from a subscription.
+    Subscription,
+    /// It was larger than the limit of `MockServer::reject_large_messages`.
+    Oversized,
+    /// It was shorter than `MockServer::min_request_bytes`.
+    Undersized,
+    /// It was skipped because of `MockServer::respond_every`.
+    Skipped,
+    /// Its response was dropped because of `MockServer::response_drop_rate`.
+    Dropped,
+    /// A `Scenario` rule, given by its index in the state and its name.
+    Scenario {
+        state: String,
+        rule: usize,
+        name: Option<String>,
+    },
+    /// A `MockServer::add_matcher` matcher or `MockServer::when_has_path`
+    /// rule, given by its index in registration order.
+    Matcher(usize),
+    /// A `MockServer::respond_by_seq` response, given by its sequence
+    /// number.
+    Seq(u64),
+    /// A `MockServer::when_query` rule, given by its index.
+    Query(usize),
+    /// The `MockServer::async_respond` responder.
+    Responder,
+    /// The `MockServer::respond_by_total_count` responder.
+    TotalCount,
+    /// A `MockServer::alternate` response, given by its index.
+    Alternate(usize),
+    /// A `MockServer::opcode_handlers` behavior answered or ignored it.
+    Opcode,
+    /// No rule matched, the next queued response was sent.
+    Queue,
+    /// No rule matched and the responses were exhausted, the response of
+    /// `MockServer::always_respond` was sent.
+    Always,
+    /// No rule matched and the responses were exhausted.
+    Exhausted,
+}
+
+/// How the server routed a received text message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchLogEntry {
+    /// Index of the connection the message was received on.
+    pub connection: usize,
+    /// The received message.
+    pub message: String,
+    /// What answered it.
+    pub rule: MatchedRule,
+}
+
+/// A ping frame received by the server, and the pong answering it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedPing {
+    /// Index of the connection the ping was received on.
+    pub connection: usize,
+    /// Payload of the ping frame.
+    pub payload: Vec<u8>,
+    /// When the ping was received.
+    pub received_at: Instant,
+    /// When the pong was sent, after `MockServer::pong_delay` if any.
+    /// `None` if the ping was not answered because of
+    /// `MockServer::pong_count`.
+    pub ponged_at: Option<Instant>,
+}
+
+/// Why the server stopped, given to the `MockServer::on_shutdown` callback.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShutdownCause {
+    /// `ServerHandle::stop` was called.
+    Explicit,
+    /// The `MockServer::lifetime` elapsed.
+    Lifetime,
+    /// The `MockServer::global_idle_timeout` fired.
+    Deadman,
+    /// The listener stopped accepting connections.
+    Error,
+}
+
+/// Why a served connection ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    Closed,
+    /// The server reset the connection, see `MockServer::reset_after`.
+    Reset,
+    /// The server dropped the connection without close handshake, see
+    /// `MockServer::abnormal_close_after`.
+    Abnormal,
+    /// Receiving from or sending to the client failed with the given error.
+    Error(String),
+}
+
+/// A served connection which ended.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disconnection {
+    /// Index of the connection which ended.
+    pub connection: usize,
+    /// Why the connection ended.
+    pub reason: DisconnectReason,
+}
+
+/// Value captured by `MockServer::capture`, and whether a message received
+/// since contains it.
+#[derive(Debug)]
+pub(crate) struct Capture {
+    value: Value,
+    seen: bool,
+}
+
+/// Traffic of a served connection which ended, see
+/// `ServerHandle::connection_stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionStats {
+    /// Index of the connection.
+    pub connection: usize,
+    /// Address of the client.
+    pub peer: Option<SocketAddr>,
+    /// Bytes written on the TCP connection, handshake and frame headers
+    /// included.
+    pub bytes_sent: u64,
+    /// Bytes read from the TCP connection, handshake and frame headers
+    /// included.
+    pub bytes_received: u64,
+    /// Text and binary messages sent.
+    pub messages_sent: usize,
+    /// Text and binary messages received.
+    pub messages_received: usize,
+    /// Time from the end of the handshake to the end of the connection.
+    pub duration: Duration,
+    /// Time from accepting the connection to completing its handshake.
+    pub handshake_duration: Duration,
+    /// Latencies of the responses sent on the connection.
+    pub response_latency: LatencyStats,
+}
+
+/// Summary of latencies: their count, and their minimum, maximum and mean
+/// durations, all zero when none was recorded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    /// Number of latencies recorded.
+    pub count: usize,
+    /// Shortest latency.
+    pub min: Duration,
+    /// Longest latency.
+    pub max: Duration,
+    /// Mean latency, rounded down to the nanosecond.
+    pub mean: Duration,
+}
+
+/// Latencies of the server, aggregated over all its connections so far,
+/// see `ServerHandle::metrics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Time from accepting a connection to completing its WebSocket
+    /// handshake, reading the request included, so it includes the time a
+    /// client takes to send the request of a connection.
+    pub handshakes: LatencyStats,
+    /// Turnaround of the responses: time from receiving a text or binary
+    /// message to sending the next text or binary message answering it,
+    /// delays injected in responses included. Messages pushed by
+    /// `ServerHandle::send`, `broadcast` or subscriptions are not
+    /// responses. A message answered with several responses counts the
+    /// first one, and messages received before a same response count once,
+    /// from the first of them.
+    pub responses: LatencyStats,
+}
+
+/// What `ServerHandle::send` does when no client is connected.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PendingSends {
+    /// Keep the messages and send them to the next connecting client.
+    #[default]
+    Buffer,
+    /// Return `SendError::NoActiveConnection`.
+    Reject,
+}
+
+/// A message pushed to a connection, not in answer to its own messages.
+#[derive(Clone)]
+pub(crate) enum Pushed {
+    /// A response of `ServerHandle::send` or `ServerHandle::broadcast`,
+    /// transformed like the queued ones.
+    Response(MockResponse),
+    /// A message of another client, see `MockServer::relay`, forwarded
+    /// unchanged.
+    Relayed(Message),
+}
+
+/// Messages pushed through `ServerHandle::send`.
+#[derive(Default)]
+pub(crate) struct Outbound {
+    connections: HashMap<usize, Sender<Pushed>>,
+    pending: Vec<MockResponse>,
+}
+
+/// State shared between a running MockServer and its ServerHandle.
+#[derive(Default)]
+pub(crate) struct State {
+    pub(crate) pending_sends: PendingSends,
+    pub(crate) draining: AtomicBool,
+    pub(crate) outbound: Mutex<Outbound>,
+    pub(crate) query_params: Mutex<QueryParams>,
+    pub(crate) dropped_connections: AtomicUsize,
+    pub(crate) dropped_responses: AtomicUsize,
+    pub(crate) failed_deliveries: AtomicUsize,
+    pub(crate) served_connections: AtomicUsize,
+    pub(crate) in_flight_connections: AtomicUsize,
+    pub(crate) oversized_messages: AtomicUsize,
+    pub(crate) text_messages: AtomicUsize,
+    pub(crate) truncated_handshakes: AtomicUsize,
+    pub(crate) sent_preambles: AtomicUsize,
+    pub(crate) hung_handshakes: AtomicUsize,
+    pub(crate) corrupted_accept_keys: AtomicUsize,
+    pub(crate) oversized_headers: AtomicUsize,
+    pub(crate) basic_credentials: Mutex<Vec<Option<(String, String)>>>,
+    pub(crate) buffer_sizes: Mutex<Vec<(usize, usize)>>,
+    pub(crate) redirects: AtomicUsize,
+    pub(crate) control_frame_violations: AtomicUsize,
+    pub(crate) received_messages: Mutex<VecDeque<RecordedMessage>>,
+    /// Maximum number of messages kept in `received_messages`, if limited.
+    pub(crate) record_limit: Option<usize>,
+    /// Received messages evicted from `received_messages` by `record_limit`.
+    pub(crate) evicted_messages: AtomicUsize,
+    /// Received messages removed by `ServerHandle::drain_received`.
+    pub(crate) drained_messages: AtomicUsize,
+    /// JSON pointers of the values captured, with their names, see
+    /// `MockServer::capture`.
+    pub(crate) capture_pointers: Vec<(String, String)>,
+    pub(crate) captures: Mutex<HashMap<usize, HashMap<String, Capture>>>,
+    /// Maximum number of entries kept in `transcript`, if it is recorded.
+    pub(crate) transcript_capacity: Option<usize>,
+    pub(crate) transcript: Mutex<VecDeque<TranscriptEntry>>,
+    /// Notified of each received message, see `ServerHandle::wait_for`.
+    pub(crate) message_watchers: Mutex<Vec<Sender<()>>>,
+    pub(crate) received_pongs: Mutex<Vec<RecordedPong>>,
+    pub(crate) unsupported_frames: Mutex<Vec<UnsupportedFrame>>,
+    pub(crate) received_pings: Mutex<Vec<RecordedPing>>,
+    pub(crate) sent_pongs: AtomicUsize,
+    pub(crate) undersized_messages: Mutex<Vec<RecordedMessage>>,
+    pub(crate) rsv_frames: Mutex<Vec<RecordedMessage>>,
+    pub(crate) raw_responses: Mutex<Vec<RecordedMessage>>,
+    pub(crate) masked_frames: AtomicUsize,
+    pub(crate) offered_versions: Mutex<Vec<Option<u8>>>,
+    /// Handshakes answered so far, see `MockServer::handshake_statuses`.
+    pub(crate) handshakes: AtomicUsize,
+    pub(crate) handshake_statuses: Mutex<Vec<u16>>,
+    pub(crate) match_log: Mutex<Vec<MatchLogEntry>>,
+    pub(crate) disconnections: Mutex<Vec<Disconnection>>,
+    pub(crate) connection_stats: Mutex<Vec<ConnectionStats>>,
+    pub(crate) handshake_latencies: Latencies,
+    pub(crate) response_latencies: Latencies,
+    pub(crate) scenario_states: Mutex<HashMap<usize, String>>,
+    pub(crate) subscriptions: Mutex<HashMap<usize, Vec<String>>>,
+    /// Responses of the queue consumed by each session of
+    /// `MockServer::resumable`, by session id.
+    pub(crate) sessions: Mutex<HashMap<String, usize>>,
+    /// Session id of each connected client resuming one.
+    pub(crate) connection_sessions: Mutex<HashMap<usize, String>>,
+    pub(crate) client_close_frame: Mutex<Option<CloseFrame<'static>>>,
+    /// Close frame sent by `MockServer::close_on_connect`, by connection.
+    pub(crate) connect_close_frames: Mutex<HashMap<usize, CloseFrame<'static>>>,
+    pub(crate) last_activity: Mutex<Option<Instant>>,
+    pub(crate) idle_timeout_fired: AtomicBool,
+    /// Dropping the sender stops the server.
+    pub(crate) stopper: Mutex<Option<Sender<()>>>,
+    pub(crate) accept_paused: AtomicBool,
+    /// Whether the listener is still bound.
+    pub(crate) listening: AtomicBool,
+    /// When the server started serving, see `MockServer::latency_ramp`.
+    pub(crate) started_at: Option<Instant>,
+    pub(crate) response_delays: Mutex<Vec<Duration>>,
+    /// Wakes up the accept loop when accepting is paused or resumed.
+    pub(crate) accept_toggle: Option<(Sender<()>, Receiver<()>)>,
+}
+
+impl State {
+    /// Register an active connection and return the receiving end of the
+    /// messages pushed to it. Buffered messages are sent to it first.
+    pub(crate) fn connect(&self, connection: usize) -> Receiver<Pushed> {
+        let (sender, receiver) = channel::unbounded();
+        let mut outbound = self.outbound.lock().unwrap();
+        for message in outbound.pending.drain(..) {
+            let _ = sender.try_send(Pushed::Response(message));
+        }
+        outbound.connections.insert(connection, sender);
+        receiver
+    }
+
+    pub(crate) fn record_message(&self, message: RecordedMessage) {
+        self.record_transcript(message.connection, Direction::Received, &message.message);
+        {
+            let mut received = self.received_messages.lock().unwrap();
+            received.push_back(message);
+            if let Some(limit) = self.record_limit {
+                // evicted under the lock so `wait_for` can count them
+                while received.len() > limit {
+                    received.pop_front();
+                    self.evicted_messages.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        let mut watchers = self.message_watchers.lock().unwrap();
+        watchers.retain(|watcher| !watcher.is_closed());
+        for watcher in watchers.iter() {
+            // a pending notification is enough to check the new messages
+            let _ = watcher.try_send(());
+        }
+    }
+
+    /// Append `message` to the transcript, if it is recorded, evicting its
+    /// oldest entry once full. As every message sent or received goes
+    /// through the transcript, values are captured from it as well.
+    pub(crate) fn record_transcript(
+        &self,
+        connection: usize,
+        direction: Direction,
+        message: &Message,
+    ) {
+        if !self.capture_pointers.is_empty() {
+            self.capture(connection, direction, message);
+        }
+        let capacity = match self.transcript_capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        let mut transcript = self.transcript.lock().unwrap();
+        if transcript.len() == capacity {
+            transcript.pop_front();
+        }
+        if capacity > 0 {
+            transcript.push_back(TranscriptEntry {
+                connection,
+                direction,
+                message: message.clone(),
+                at: Instant::now(),
+            });
+        }
+    }
+
+    /// Mark the captures of `connection` a received `message` contains as
+    /// seen, then capture the values of `message`.
+    fn capture(&self, connection: usize, direction: Direction, message: &Message) {
+        let text = match message {
+            Message::Text(text) => text,
+            _ => return,
+        };
+        let mut captures = self.captures.lock().unwrap();
+        let captures = captures.entry(connection).or_default();
+        if direction == Direction::Received {
+            for capture in captures.values_mut() {
+                capture.seen |= match &capture.value {
+                    Value::String(value) => text.contains(value.as_str()),
+                    value => text.contains(&value.to_string()),
+                };
+            }
+        }
+        let request: Value = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        for (pointer, name) in &self.capture_pointers {
+            if let Some(value) = request.pointer(pointer) {
+                if captures.get(name).map(|capture| &capture.value) != Some(value) {
+                    let value = value.clone();
+                    captures.insert(name.clone(), Capture { value, seen: false });
+                }
+            }
+        }
+    }
+
+    pub(crate) fn toggle_accept(&self) {
+        if let Some((toggle, _)) = &self.accept_toggle {
+            let _ = toggle.try_send(());
+        }
+    }
+
+    pub(crate) fn set_scenario_state(&self, connection: usize, scenario_state: &str) {
+        self.scenario_states
+            .lock()
+            .unwrap()
+            .insert(connection, scenario_state.into());
+    }
+
+    pub(crate) fn log_match(&self, connection: usize, message: &str, rule: MatchedRule) {
+        self.match_log.lock().unwrap().push(MatchLogEntry {
+            connection,
+            message: message.into(),
+            rule,
+        });
+    }
+
+    /// Record inbound activity, for `MockServer::global_idle_timeout`.
+    pub(crate) fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Push `message` to the active connections, but `except`, and return
+    /// how many of them it was delivered to. Connections which ended
+    /// meanwhile miss it, and are counted as failed deliveries.
+    pub(crate) fn broadcast(&self, message: Pushed, except: Option<usize>) -> usize {
+        let outbound = self.outbound.lock().unwrap();
+        let mut delivered = 0;
+        for (_, sender) in outbound
+            .connections
+            .iter()
+            .filter(|(&index, _)| Some(index) != except)
+        {
+            match sender.try_send(message.clone()) {
+                Ok(()) => delivered += 1,
+                Err(_) => {
+                    self.failed_deliveries.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Make every active connection close, by dropping the senders of their
+    /// outbound messages.
+    pub(crate) fn close_all(&self) {
+        self.outbound.lock().unwrap().connections.clear();
+    }
+
+    pub(crate) fn set_subscriptions(&self, connection: usize, subscriptions: &[String]) {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .insert(connection, subscriptions.to_vec());
+    }
+
+    /// Attach `connection` to the session `id`, created if unknown, and
+    /// return the number of queued responses its previous connections
+    /// consumed.
+    pub(crate) fn resume_session(&self, connection: usize, id: &str) -> usize {
+        let consumed = *self.sessions.lock().unwrap().entry(id.into()).or_insert(0);
+        self.connection_sessions
+            .lock()
+            .unwrap()
+            .insert(connection, id.into());
+        consumed
+    }
+
+    /// Count a queued response consumed by the session of `connection`, if
+    /// it resumes one.
+    pub(crate) fn consume_session(&self, connection: usize) {
+        if let Some(id) = self.connection_sessions.lock().unwrap().get(&connection) {
+            *self.sessions.lock().unwrap().entry(id.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Unregister a connection which ended and record why.
+    pub(crate) fn disconnect(&self, connection: usize, reason: DisconnectReason) {
+        self.outbound
+            .lock()
+            .unwrap()
+            .connections
+            .remove(&connection);
+        self.subscriptions.lock().unwrap().remove(&connection);
+        self.connection_sessions.lock().unwrap().remove(&connection);
+        self.disconnections
+            .lock()
+            .unwrap()
+            .push(Disconnection { connection, reason });
+    }
+}
+
+/// StatsRecorder records the `ConnectionStats` of a connection once
+/// dropped, so they are recorded however the connection ended, even if it
+/// failed or its task panicked.
+pub(crate) struct StatsRecorder<'a> {
+    state: &'a State,
+    connection: usize,
+    peer: Option<SocketAddr>,
+    traffic: Arc<Traffic>,
+    started: Instant,
+    handshake_duration: Duration,
+}
+
+impl<'a> StatsRecorder<'a> {
+    /// Start recording the stats of a connection whose handshake completed
+    /// `handshake_duration` after it was accepted.
+    pub(crate) fn new(
+        state: &'a State,
+        connection: usize,
+        peer: Option<SocketAddr>,
+        traffic: Arc<Traffic>,
+        handshake_duration: Duration,
+    ) -> Self {
+        state.handshake_latencies.record(handshake_duration);
+        Self {
+            state,
+            connection,
+            peer,
+            traffic,
+            started: Instant::now(),
+            handshake_duration,
+        }
+    }
+}
+
+impl Drop for StatsRecorder<'_> {
+    fn drop(&mut self) {
+        let traffic = &self.traffic;
+        let stats = ConnectionStats {
+            connection: self.connection,
+            peer: self.peer,
+            bytes_sent: traffic.bytes_sent.load(Ordering::SeqCst),
+            bytes_received: traffic.bytes_received.load(Ordering::SeqCst),
+            messages_sent: traffic.messages_sent.load(Ordering::SeqCst),
+            messages_received: traffic.messages_received.load(Ordering::SeqCst),
+            duration: self.started.elapsed(),
+            handshake_duration: self.handshake_duration,
+            response_latency: traffic.response_latencies.stats(),
+        };
+        if let Ok(mut connection_stats) = self.state.connection_stats.lock() {
+            connection_stats.push(stats);
+        }
+    }
+}
+
+/// ServerHandle is returned by `MockServer::start` and gives access to
+/// the running server.
+pub struct ServerHandle {
+    host: String,
+    port: u16,
+    state: Arc<State>,
+    /// Outcome of the server task, sent when it ends.
+    task: Receiver<thread::Result<()>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        host: String,
+        port: u16,
+        state: Arc<State>,
+        task: Receiver<thread::Result<()>>,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            state,
+            task,
+        }
+    }
+
+    /// Wait until the server task ends: it keeps accepting connections
+    /// until `stop` is called, its `MockServer::lifetime` elapsed or its
+    /// `MockServer::global_idle_timeout` fired, so awaiting it blocks until
+    /// then. Once it returns, the listener is dropped and its port can be
+    /// bound again. If the task panicked, the panic is resumed here.
+    /// Dropping the handle instead detaches the task, which keeps running.
+    pub async fn join(self) {
+        if let Ok(Err(panic)) = self.task.recv().await {
+            panic::resume_unwind(panic);
+        }
+    }
+
+    /// Host the server is listening on.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Port the server is listening on. If the server was started
+    /// with port 0, this is the port picked by the OS.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// WebSocket URL of the server, e.g. `ws://localhost:34567`.
+    pub fn endpoint(&self) -> String {
+        format!("ws://{}:{}", self.host, self.port)
+    }
+
+    /// Put the server in draining state: connected clients are still
+    /// served, but new WebSocket connections are refused with a
+    /// 503 Service Unavailable and the health check endpoint reports 503.
+    pub fn drain(&self) {
+        self.state.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop accepting new connections while the connected clients are
+    /// still served, like a server at capacity. New connection attempts
+    /// wait in the listener backlog until `resume_accept` is called: the
+    /// TCP connection is established but the WebSocket handshake is not
+    /// answered. Attempts beyond the backlog are refused or retried
+    /// depending on the OS, see `MockServer::backlog`.
+    pub fn pause_accept(&self) {
+        self.state.accept_paused.store(true, Ordering::SeqCst);
+        self.state.toggle_accept();
+    }
+
+    /// Accept new connections again after `pause_accept`, starting with
+    /// the attempts waiting in the backlog.
+    pub fn resume_accept(&self) {
+        self.state.accept_paused.store(false, Ordering::SeqCst);
+        self.state.toggle_accept();
+    }
+
+    /// Stop the server: it stops accepting connections and closes the
+    /// active ones with a 1001 going away close frame. The server task ends
+    /// shortly after, see `stopped` to wait for it.
+    pub fn stop(&self) {
+        self.state.stopper.lock().unwrap().take();
+    }
+
+    /// Wait until the server task ends, e.g. after `stop`, like `join` but
+    /// without consuming the handle: once it returns, the listener is
+    /// dropped and its port can be bound again right away. If the task
+    /// panicked, the panic is resumed here, and `join` then returns at once.
+    pub async fn stopped(&self) {
+        if let Ok(Err(panic)) = self.task.recv().await {
+            panic::resume_unwind(panic);
+        }
+    }
+
+    /// Whether the server still listens on its port. It stops listening
+    /// shortly after `stop` is called, or after the end of its
+    /// `MockServer::lifetime`, once the listener is dropped: the port can
+    /// then be bound again, e.g. to restart a server on the same port.
+    pub fn is_listening(&self) -> bool {
+        self.state.listening.load(Ordering::SeqCst)
+    }
+
+    /// Whether `MockServer::global_idle_timeout` fired, closing every
+    /// connection and stopping the server.
+    pub fn idle_timeout_fired(&self) -> bool {
+        self.state.idle_timeout_fired.load(Ordering::SeqCst)
+    }
+
+    /// Whether `drain` was called.
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::SeqCst)
+    }
+
+    /// Query parameters sent by the last connected client during the
+    /// handshake. Values are URL-decoded.
+    /// The map is empty if no client connected yet or if the last one
+    /// did not send any query string.
+    pub fn query_params(&self) -> QueryParams {
+        self.state.query_params.lock().unwrap().clone()
+    }
+
+    /// Number of connections dropped before the handshake completed
+    /// because of the `flaky` mode.
+    pub fn dropped_connections(&self) -> usize {
+        self.state.dropped_connections.load(Ordering::SeqCst)
+    }
+
+    /// Number of messages left unanswered because of the
+    /// `MockServer::response_drop_rate` mode.
+    pub fn dropped_responses(&self) -> usize {
+        self.state.dropped_responses.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections which completed the WebSocket handshake.
+    pub fn served_connections(&self) -> usize {
+        self.state.served_connections.load(Ordering::SeqCst)
+    }
+
+    /// Number of messages answered with the error of
+    /// `MockServer::reject_large_messages` because they were too large.
+    pub fn oversized_messages(&self) -> usize {
+        self.state.oversized_messages.load(Ordering::SeqCst)
+    }
+
+    /// Number of clients currently connected, whose handshake completed.
+    pub fn connected_clients(&self) -> usize {
+        self.state.outbound.lock().unwrap().connections.len()
+    }
+
+    /// Number of accepted connections currently being handled, including
+    /// the ones whose handshake is in progress. Connections waiting for a
+    /// permit of `MockServer::concurrency_limit` are not counted.
+    pub fn in_flight_connections(&self) -> usize {
+        self.state.in_flight_connections.load(Ordering::SeqCst)
+    }
+
+    /// Number of handshakes cut short by `MockServer::truncate_handshake`.
+    pub fn truncated_handshakes(&self) -> usize {
+        self.state.truncated_handshakes.load(Ordering::SeqCst)
+    }
+
+    /// Number of handshakes left unanswered by `MockServer::hang_handshake`.
+    pub fn hung_handshakes(&self) -> usize {
+        self.state.hung_handshakes.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections sent the preamble of `MockServer::preamble`
+    /// before their handshake response.
+    pub fn sent_preambles(&self) -> usize {
+        self.state.sent_preambles.load(Ordering::SeqCst)
+    }
+
+    /// Number of handshakes answered with a wrong accept key because of
+    /// `MockServer::corrupt_accept_key`.
+    pub fn corrupted_accept_keys(&self) -> usize {
+        self.state.corrupted_accept_keys.load(Ordering::SeqCst)
+    }
+
+    /// Number of handshakes rejected with a 431 because their headers
+    /// exceeded `MockServer::max_header_size`.
+    pub fn oversized_headers(&self) -> usize {
+        self.state.oversized_headers.load(Ordering::SeqCst)
+    }
+
+    /// Number of WebSocket handshakes answered with the redirect of
+    /// `MockServer::redirect`.
+    pub fn redirects(&self) -> usize {
+        self.state.redirects.load(Ordering::SeqCst)
+    }
+
+    /// Number of connections closed by `MockServer::strict_control_frames`
+    /// because the client sent an invalid control frame.
+    pub fn control_frame_violations(&self) -> usize {
+        self.state.control_frame_violations.load(Ordering::SeqCst)
+    }
+
+    /// Text and binary messages received so far on every connection,
+    /// in the order they were received. With `MockServer::record_limit`,
+    /// only the retained most recent messages are returned.
+    pub fn received_messages(&self) -> Vec<RecordedMessage> {
+        self.state
+            .received_messages
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Take the text and binary messages received so far, in the order they
+    /// were received, leaving none recorded, e.g. to assert each phase of a
+    /// multi-phase test on its own messages without tracking indices.
+    /// The messages are taken at once under the lock the connections record
+    /// them with: a message being received concurrently is either returned
+    /// by this call or recorded for the next one, never lost nor returned
+    /// twice. Messages evicted by `MockServer::record_limit` are not
+    /// returned, and drained messages no longer match `wait_for`.
+    pub fn drain_received(&self) -> Vec<RecordedMessage> {
+        let mut received = self.state.received_messages.lock().unwrap();
+        self.state
+            .drained_messages
+            .fetch_add(received.len(), Ordering::SeqCst);
+        std::mem::take(&mut *received).into()
+    }
+
+    /// Number of received messages evicted from `received_messages` once
+    /// `MockServer::record_limit` was reached: when it is not 0, the
+    /// recorded messages were truncated and miss the oldest ones.
+    pub fn evicted_messages(&self) -> usize {
+        self.state.evicted_messages.load(Ordering::SeqCst)
+    }
+
+    /// Messages of the transcript recorded by `MockServer::record_transcript`
+    /// on every connection, from the oldest to the newest retained one.
+    pub fn transcript(&self) -> Vec<TranscriptEntry> {
+        self.state
+            .transcript
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The `n` most recent messages of the transcript, or fewer if fewer
+    /// are retained, from the oldest to the newest one: the tail of a long
+    /// conversation, to debug a failure without dumping all of it.
+    pub fn recent_transcript(&self, n: usize) -> Vec<TranscriptEntry> {
+        let transcript = self.state.transcript.lock().unwrap();
+        let skipped = transcript.len().saturating_sub(n);
+        transcript.iter().skip(skipped).cloned().collect()
+    }
+
+    /// Text messages ignored because they were shorter than
+    /// `MockServer::min_request_bytes`, in the order they were received.
+    pub fn undersized_messages(&self) -> Vec<RecordedMessage> {
+        self.state.undersized_messages.lock().unwrap().clone()
+    }
+
+    /// Responses sent in frames with the reserved bits of
+    /// `MockServer::set_rsv`, in the order they were sent.
+    pub fn rsv_frames(&self) -> Vec<RecordedMessage> {
+        self.state.rsv_frames.lock().unwrap().clone()
+    }
+
+    /// Bytes written by `MockServer::raw_bytes_response`, as binary
+    /// messages, in the order they were written.
+    pub fn raw_responses(&self) -> Vec<RecordedMessage> {
+        self.state.raw_responses.lock().unwrap().clone()
+    }
+
+    /// Delay applied to each response by `MockServer::latency_ramp`, in the
+    /// order the responses were sent.
+    pub fn response_delays(&self) -> Vec<Duration> {
+        self.state.response_delays.lock().unwrap().clone()
+    }
+
+    /// Number of response frames masked because of
+    /// `MockServer::mask_server_frames`.
+    pub fn masked_frames(&self) -> usize {
+        self.state.masked_frames.load(Ordering::SeqCst)
+    }
+
+    /// `Sec-WebSocket-Version` offered by each WebSocket handshake request,
+    /// in the order they were received. `None` if a request had no valid
+    /// version.
+    pub fn offered_versions(&self) -> Vec<Option<u8>> {
+        self.state.offered_versions.lock().unwrap().clone()
+    }
+
+    /// Wait until a text or binary message matching `predicate` is
+    /// received, e.g. the `subscribe` message of a client, and return it.
+    /// Messages received before the call are checked too, so the first
+    /// matching message is returned whenever it was received. Returns a
+    /// `WaitError::Timeout` if no message matched within `timeout`.
+    pub async fn wait_for<F>(
+        &self,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<RecordedMessage, WaitError>
+    where
+        F: Fn(&Message) -> bool,
+    {
+        let (watcher, notified) = channel::bounded(1);
+        self.state.message_watchers.lock().unwrap().push(watcher);
+        let wait = async {
+            let mut checked: usize = 0;
+            loop {
+                {
+                    let messages = self.state.received_messages.lock().unwrap();
+                    // `checked` counts evicted and drained messages too, so
+                    // it stays valid once messages are removed
+                    let removed = self.state.evicted_messages.load(Ordering::SeqCst)
+                        + self.state.drained_messages.load(Ordering::SeqCst);
+                    let matching = messages
+                        .iter()
+                        .skip(checked.saturating_sub(removed))
+                        .find(|recorded| predicate(&recorded.message));
+                    if let Some(recorded) = matching {
+                        return recorded.clone();
+                    }
+                    checked = removed + messages.len();
+                }
+                // the watcher stays registered while its receiver lives
+                let _ = notified.recv().await;
+            }
+        };
+        async_std::future::timeout(timeout, wait)
+            .await
+            .map_err(|_| WaitError::Timeout)
+    }
+
+    /// Pong frames received so far on every connection, in the order they
+    /// were received. Useful to assert a client answers keepalive pings.
+    pub fn received_pongs(&self) -> Vec<RecordedPong> {
+        self.state.received_pongs.lock().unwrap().clone()
+    }
+
+    /// Frames of reserved opcodes received so far on every connection, in
+    /// the order they were received, see `MockServer::on_unsupported`.
+    pub fn unsupported_frames(&self) -> Vec<UnsupportedFrame> {
+        self.state.unsupported_frames.lock().unwrap().clone()
+    }
+
+    /// Ping frames received so far on every connection, in the order they
+    /// were received, with when they were answered.
+    pub fn received_pings(&self) -> Vec<RecordedPing> {
+        self.state.received_pings.lock().unwrap().clone()
+    }
+
+    /// How each text message received so far was routed, in the order they
+    /// were received, to debug why a response was chosen.
+    pub fn match_log(&self) -> Vec<MatchLogEntry> {
+        self.state.match_log.lock().unwrap().clone()
+    }
+
+    /// Number of pongs sent in answer to pings, on every connection.
+    pub fn sent_pongs(&self) -> usize {
+        self.state.sent_pongs.load(Ordering::SeqCst)
+    }
+
+    /// Close frame sent by the last client which closed its connection, to
+    /// assert on its close code and reason. `None` if no client closed its
+    /// connection yet, or if it closed it without a close frame payload.
+    pub fn client_close_frame(&self) -> Option<CloseFrame<'static>> {
+        self.state.client_close_frame.lock().unwrap().clone()
+    }
+
+    /// Ids of the sessions of `MockServer::resumable` a client is connected
+    /// with, sorted. A session stays known to the server once its client
+    /// disconnected, so a reconnecting client can resume it.
+    pub fn active_sessions(&self) -> Vec<String> {
+        let mut sessions: Vec<_> = self
+            .state
+            .connection_sessions
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        sessions.sort();
+        sessions
+    }
+
+    /// Number of queued responses consumed by each session of
+    /// `MockServer::resumable`, connected or not, by session id.
+    pub fn sessions(&self) -> HashMap<String, usize> {
+        self.state.sessions.lock().unwrap().clone()
+    }
+
+    /// Close frame sent to the given connection right after its handshake
+    /// because of `MockServer::close_on_connect`. `None` if the server does
+    /// not close connections on connect or the connection does not exist.
+    pub fn connect_close_frame(&self, connection: usize) -> Option<CloseFrame<'static>> {
+        self.state
+            .connect_close_frames
+            .lock()
+            .unwrap()
+            .get(&connection)
+            .cloned()
+    }
+
+    /// Current `Scenario` state of the given connection, or its final state
+    /// if it ended. `None` if the server has no scenario or the connection
+    /// does not exist.
+    pub fn scenario_state(&self, connection: usize) -> Option<String> {
+        self.state
+            .scenario_states
+            .lock()
+            .unwrap()
+            .get(&connection)
+            .cloned()
+    }
+
+    /// Ids of the subscriptions the given connection is subscribed to, in
+    /// the order it subscribed. Empty once the connection ended.
+    pub fn active_subscriptions(&self, connection: usize) -> Vec<String> {
+        self.state
+            .subscriptions
+            .lock()
+            .unwrap()
+            .get(&connection)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Served connections which ended so far, in the order they ended.
+    pub fn disconnections(&self) -> Vec<Disconnection> {
+        self.state.disconnections.lock().unwrap().clone()
+    }
+
+    /// HTTP status answered to each WebSocket handshake, in order: 101 for
+    /// accepted connections, the status given to
+    /// `MockServer::handshake_statuses`, or the one of the rejections of
+    /// `MockServer::max_header_size`, `MockServer::require_version`,
+    /// `MockServer::redirect` and `drain`.
+    pub fn handshake_statuses(&self) -> Vec<u16> {
+        self.state.handshake_statuses.lock().unwrap().clone()
+    }
+
+    /// Credentials presented to each handshake checked by
+    /// `MockServer::basic_auth`, in order, as a user and a password, or
+    /// `None` if the `Authorization: Basic` header was missing or malformed.
+    pub fn basic_credentials(&self) -> Vec<Option<(String, String)>> {
+        self.state.basic_credentials.lock().unwrap().clone()
+    }
+
+    /// Effective receive and send buffer sizes of the accepted sockets, in
+    /// the order they were accepted, as reported by the OS once
+    /// `MockServer::recv_buffer_size` or `send_buffer_size` set them.
+    pub fn buffer_sizes(&self) -> Vec<(usize, usize)> {
+        self.state.buffer_sizes.lock().unwrap().clone()
+    }
+
+    /// Traffic of the served connections which ended so far, in the order
+    /// they ended, e.g. to check the framing overhead of a client.
+    pub fn connection_stats(&self) -> Vec<ConnectionStats> {
+        self.state.connection_stats.lock().unwrap().clone()
+    }
+
+    /// Latencies of the handshakes and responses of all the connections so
+    /// far, including the ones still open, e.g. to check the overhead of the
+    /// server or the accuracy of injected delays. See `connection_stats` for
+    /// the latencies of each connection. Taken while a response is sent,
+    /// the snapshot may be slightly off.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            handshakes: self.state.handshake_latencies.stats(),
+            responses: self.state.response_latencies.stats(),
+        }
+    }
+
+    /// Panic if the server received more than `expected_count` text or
+    /// binary messages, listing the surplus ones.
+    /// Useful for strict contract tests where the client must not send
+    /// more than the scripted conversation.
+    pub fn assert_no_extra_messages(&self, expected_count: usize) {
+        let received = self.received_messages();
+        if received.len() > expected_count {
+            let surplus: Vec<String> = received[expected_count..]
+                .iter()
+                .map(|recorded| format!("  - {}", recorded.message))
+                .collect();
+            panic!(
+                "expected at most {} messages but received {}, surplus messages:\n{}",
+                expected_count,
+                received.len(),
+                surplus.join("\n")
+            );
+        }
+    }
+
+    /// Panic unless the server received a message matching `before` ahead
+    /// of a message matching `after`, to check happens-before constraints
+    /// of a protocol, e.g. that a client authenticates before subscribing.
+    /// When the predicates match several messages, the first occurrence of
+    /// each is compared, across all connections. Panics if either message
+    /// was not received.
+    pub fn assert_order(
+        &self,
+        before: impl Fn(&Message) -> bool,
+        after: impl Fn(&Message) -> bool,
+    ) {
+        let received = self.received_messages();
+        let position = |predicate: &dyn Fn(&Message) -> bool| {
+            received
+                .iter()
+                .position(|recorded| predicate(&recorded.message))
+        };
+        match (position(&before), position(&after)) {
+            (Some(first), Some(second)) if first < second => {}
+            (Some(first), Some(second)) => panic!(
+                "expected {} (message #{}) to be received before {} (message #{})",
+                received[first].message, first, received[second].message, second
+            ),
+            (first, second) => panic!(
+                "expected ordered messages but {} not received",
+                match (first, second) {
+                    (None, None) => "neither was",
+                    (None, _) => "the `before` one was",
+                    _ => "the `after` one was",
+                }
+            ),
+        }
+    }
+
+    /// Values captured by `MockServer::capture` on `connection`, by name.
+    pub fn captures(&self, connection: usize) -> HashMap<String, Value> {
+        self.state
+            .captures
+            .lock()
+            .unwrap()
+            .get(&connection)
+            .map(|captures| {
+                captures
+                    .iter()
+                    .map(|(name, capture)| (name.clone(), capture.value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Panic unless, on each connection a value was captured as `name` by
+    /// `MockServer::capture`, a message received after it was captured
+    /// contains it: a string value must be part of the text of the message,
+    /// e.g. a token in `"Bearer <token>"`, and other values part of it as
+    /// JSON. Also panics if no value was captured as `name`.
+    pub fn assert_contains(&self, name: &str) {
+        // the lock is released before panicking, so it is not poisoned
+        let failure = {
+            let captures = self.state.captures.lock().unwrap();
+            let mut connections: Vec<_> = captures
+                .iter()
+                .filter_map(|(connection, captures)| Some((*connection, captures.get(name)?)))
+                .collect();
+            connections.sort_by_key(|(connection, _)| *connection);
+            if connections.is_empty() {
+                Some(format!("no value was captured as {}", name))
+            } else {
+                connections
+                    .into_iter()
+                    .find(|(_, capture)| !capture.seen)
+                    .map(|(connection, capture)| {
+                        format!(
+                            "no message received on connection {} after capturing {} contained {}",
+                            connection, name, capture.value
+                        )
+                    })
+            }
+        };
+        if let Some(failure) = failure {
+            panic!("{}", failure);
+        }
+    }
+
+    /// Push `message` to every connected client right away, independently
+    /// of the messages they send.
+    /// When no client is connected, the message is either kept for the next
+    /// connecting client or rejected, depending on `MockServer::pending_sends`.
+    pub fn send(&self, message: MockResponse) -> Result<(), SendError> {
+        let mut outbound = self.state.outbound.lock().unwrap();
+        if outbound.connections.is_empty() {
+            return match self.state.pending_sends {
+                PendingSends::Buffer => {
+                    outbound.pending.push(message);
+                    Ok(())
+                }
+                PendingSends::Reject => Err(SendError::NoActiveConnection),
+            };
+        }
+        for sender in outbound.connections.values() {
+            let _ = sender.try_send(Pushed::Response(message.clone()));
+        }
+        Ok(())
+    }
+
+    /// Push `message` to every client connected right now, and return how
+    /// many of them it was delivered to. Unlike `send`, the message is not
+    /// kept when no client is connected, and clients connecting after the
+    /// call do not receive it. A client disconnecting during the broadcast
+    /// is skipped, and counted by `failed_deliveries`.
+    pub fn broadcast(&self, message: MockResponse) -> usize {
+        self.state.broadcast(Pushed::Response(message), None)
+    }
+
+    /// Number of messages of `broadcast` or `MockServer::relay` which could
+    /// not be delivered to a client because it disconnected meanwhile.
+    pub fn failed_deliveries(&self) -> usize {
+        self.state.failed_deliveries.load(Ordering::SeqCst)
+    }
+}
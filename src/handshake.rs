@@ -0,0 +1,288 @@
+use crate::error::ConfigError;
+use crate::handle::QueryParams;
+use async_std::io::{self, Read, ReadExt};
+use async_tungstenite::tungstenite::handshake::derive_accept_key;
+use async_tungstenite::tungstenite::http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION,
+};
+use async_tungstenite::tungstenite::http::Uri;
+use std::fmt;
+
+/// Headers of the 101 response required by the WebSocket handshake.
+const RESERVED_HEADERS: [&str; 3] = ["upgrade", "connection", "sec-websocket-accept"];
+
+/// Maximum number of headers of a request head, the one of tungstenite, so
+/// requests it would accept are never rejected before reaching it.
+const MAX_HEADERS: usize = 124;
+
+/// Maximum size of a request head read before the WebSocket handshake.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Error reading a request head over `MAX_HEAD_SIZE` or with more headers
+/// than are parsed.
+#[derive(Debug)]
+pub(crate) struct HeadTooLarge;
+
+impl fmt::Display for HeadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("request head too large")
+    }
+}
+
+impl std::error::Error for HeadTooLarge {}
+
+impl HeadTooLarge {
+    /// Whether reading a request head failed with `error` as it was too large.
+    pub(crate) fn caused(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|error| error.is::<Self>())
+    }
+}
+
+/// Head of an HTTP request read from a freshly accepted connection.
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) upgrade: bool,
+    /// Value of the `Sec-WebSocket-Key` header.
+    pub(crate) key: Option<String>,
+    /// Value of the `Sec-WebSocket-Version` header, if it is a number.
+    pub(crate) version: Option<u8>,
+    /// Length of the head, the bytes read after it belong to the body or,
+    /// once upgraded, to the WebSocket frames.
+    pub(crate) len: usize,
+}
+
+impl RequestHead {
+    /// Read a request head from `stream`.
+    /// Returns the parsed head along with all the bytes read, so they can be
+    /// replayed to tungstenite if the request is a WebSocket upgrade.
+    pub(crate) async fn read<S: Read + Unpin>(stream: &mut S) -> io::Result<(Self, Vec<u8>)> {
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let len = stream.read(&mut chunk).await?;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buffer.extend_from_slice(&chunk[..len]);
+
+            if let Some(head) = Self::parse(&buffer)? {
+                return Ok((head, buffer));
+            }
+            if buffer.len() > MAX_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, HeadTooLarge));
+            }
+        }
+    }
+
+    fn parse(buffer: &[u8]) -> io::Result<Option<Self>> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        let status = request.parse(buffer).map_err(|error| match error {
+            httparse::Error::TooManyHeaders => {
+                io::Error::new(io::ErrorKind::InvalidData, HeadTooLarge)
+            }
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        })?;
+        let len = match status {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        let upgrade = request.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("upgrade")
+                && String::from_utf8_lossy(header.value)
+                    .to_ascii_lowercase()
+                    .contains("websocket")
+        });
+
+        let key = request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("sec-websocket-key"))
+            .map(|header| String::from_utf8_lossy(header.value).trim().to_string());
+
+        let version = request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("sec-websocket-version"))
+            .and_then(|header| String::from_utf8_lossy(header.value).trim().parse().ok());
+
+        Ok(Some(Self {
+            method: request.method.unwrap_or_default().into(),
+            path: request.path.unwrap_or_default().into(),
+            upgrade,
+            key,
+            version,
+            len,
+        }))
+    }
+}
+
+/// Build the 101 Switching Protocols response accepting the handshake of
+/// `key`, with the extra `headers`, as tungstenite would write it.
+pub(crate) fn switching_protocols(key: &str, headers: &HeaderMap) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n",
+        derive_accept_key(key.as_bytes())
+    )
+    .into_bytes();
+    for (name, value) in headers {
+        response.extend_from_slice(name.as_str().as_bytes());
+        response.extend_from_slice(b": ");
+        response.extend_from_slice(value.as_bytes());
+        response.extend_from_slice(b"\r\n");
+    }
+    response.extend_from_slice(b"\r\n");
+    response
+}
+
+/// Accept key which does not match `key`: the one of `key` with its bytes
+/// reversed, so it looks like a valid key but fails the client check.
+pub(crate) fn corrupt_accept_key(key: &str) -> String {
+    let reversed: Vec<u8> = key.bytes().rev().collect();
+    derive_accept_key(&reversed)
+}
+
+/// Size of the header lines of a request: their names and values, plus
+/// the `: ` separator and the CRLF ending each line.
+pub(crate) fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+/// Credentials of the `Authorization: Basic` header of a request, as a
+/// user and a password, or `None` if the header is missing, of another
+/// scheme, or not base64 encoded `user:password` UTF-8 text.
+pub(crate) fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.into(), password.into()))
+}
+
+/// Parse the query string of a handshake request URI.
+/// Keys and values are URL-decoded and duplicated keys keep all their values.
+pub(crate) fn parse_query(uri: &Uri) -> QueryParams {
+    let mut params = QueryParams::new();
+    if let Some(query) = uri.query() {
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            params
+                .entry(key.into_owned())
+                .or_default()
+                .push(value.into_owned());
+        }
+    }
+    params
+}
+
+/// Build the extra headers added to the 101 Switching Protocols response.
+/// Headers required by the WebSocket handshake cannot be overridden.
+pub(crate) fn response_headers(headers: &[(String, String)]) -> Result<HeaderMap, ConfigError> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return Err(ConfigError::ReservedHeader(name.clone()));
+        }
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| ConfigError::InvalidHeader(name.clone()))?;
+        let header_value =
+            HeaderValue::from_str(value).map_err(|_| ConfigError::InvalidHeader(name.clone()))?;
+        map.append(header_name, header_value);
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query_without_query_string() {
+        let uri: Uri = "/".parse().unwrap();
+        assert!(parse_query(&uri).is_empty());
+    }
+
+    #[test]
+    fn parse_query_with_duplicated_and_encoded_values() {
+        let uri: Uri = "/?token=abc&tag=a&tag=b%20c&name=hello+world"
+            .parse()
+            .unwrap();
+        let params = parse_query(&uri);
+
+        assert_eq!(params["token"], vec!["abc"]);
+        assert_eq!(params["tag"], vec!["a", "b c"]);
+        assert_eq!(params["name"], vec!["hello world"]);
+    }
+
+    #[test]
+    fn parse_request_head() {
+        let head = RequestHead::parse(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "/health");
+        assert!(!head.upgrade);
+
+        let head = RequestHead::parse(b"GET / HTTP/1.1\r\nUpgrade: WebSocket\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(head.upgrade);
+
+        assert!(RequestHead::parse(b"GET / HTTP/1.1\r\nHost:")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn response_headers_reject_reserved_and_invalid_headers() {
+        assert_eq!(
+            response_headers(&[("Sec-WebSocket-Accept".into(), "abc".into())]),
+            Err(ConfigError::ReservedHeader("Sec-WebSocket-Accept".into()))
+        );
+        assert_eq!(
+            response_headers(&[("X-Bad Header".into(), "abc".into())]),
+            Err(ConfigError::InvalidHeader("X-Bad Header".into()))
+        );
+
+        let map = response_headers(&[
+            ("X-Server-Version".into(), "1.2.3".into()),
+            ("Set-Cookie".into(), "a=1".into()),
+            ("Set-Cookie".into(), "b=2".into()),
+        ])
+        .unwrap();
+        assert_eq!(map["x-server-version"], "1.2.3");
+        assert_eq!(map.get_all("set-cookie").iter().count(), 2);
+    }
+
+    #[test]
+    fn build_switching_protocols_response() {
+        let response = switching_protocols("dGhlIHNhbXBsZSBub25jZQ==", &HeaderMap::new());
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn parse_basic_credentials() {
+        let credentials = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+            basic_credentials(&headers)
+        };
+        assert_eq!(
+            credentials("Basic dXNlcjpwYTpzcw=="),
+            Some(("user".into(), "pa:ss".into()))
+        );
+        assert_eq!(credentials("Bearer dXNlcjpwYTpzcw=="), None);
+        assert_eq!(credentials("Basic not base64!"), None);
+        assert_eq!(credentials("Basic dXNlcg=="), None);
+        assert_eq!(basic_credentials(&HeaderMap::new()), None);
+    }
+}
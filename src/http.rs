@@ -0,0 +1,101 @@
+use async_std::io::{self, Write, WriteExt};
+use async_tungstenite::tungstenite::http::StatusCode;
+
+/// A canned HTTP response served to plain HTTP GET requests on `path`.
+#[derive(Clone)]
+pub(crate) struct HttpRoute {
+    pub(crate) path: String,
+    pub(crate) status: u16,
+    pub(crate) body: String,
+    pub(crate) content_type: String,
+}
+
+/// Answer a plain HTTP request with the first route matching its method and
+/// path, or if none does, with the `fallback` chunks or a 404.
+/// The query string is ignored when matching the path.
+pub(crate) async fn respond<S: Write + Unpin>(
+    stream: &mut S,
+    routes: &[HttpRoute],
+    fallback: Option<&[String]>,
+    method: &str,
+    path: &str,
+) -> io::Result<()> {
+    let path = path.split('?').next().unwrap_or_default();
+    match routes
+        .iter()
+        .find(|route| method == "GET" && route.path == path)
+    {
+        Some(route) => write_response(stream, route.status, &route.content_type, &route.body).await,
+        None => match fallback {
+            Some(chunks) => write_chunked_response(stream, 200, "text/plain", chunks).await,
+            None => write_response(stream, 404, "text/plain", "Not Found").await,
+        },
+    }
+}
+
+pub(crate) async fn write_response<S: Write + Unpin>(
+    stream: &mut S,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write_response_with_headers(stream, status, &[], content_type, body).await
+}
+
+/// Write a response like `write_response`, with the extra `headers`.
+pub(crate) async fn write_response_with_headers<S: Write + Unpin>(
+    stream: &mut S,
+    status: u16,
+    headers: &[(&str, &str)],
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        headers,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Write a response whose body is sent with the chunked transfer coding,
+/// each of the `chunks` written and flushed on its own, as
+/// `<size in hex>\r\n<chunk>\r\n`, then the `0\r\n\r\n` last chunk.
+/// Empty chunks are skipped, as a chunk of size 0 ends the body.
+pub(crate) async fn write_chunked_response<S: Write + Unpin>(
+    stream: &mut S,
+    status: u16,
+    content_type: &str,
+    chunks: &[String],
+) -> io::Result<()> {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        status, reason, content_type
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    for chunk in chunks.iter().filter(|chunk| !chunk.is_empty()) {
+        let chunk = format!("{:x}\r\n{}\r\n", chunk.len(), chunk);
+        stream.write_all(chunk.as_bytes()).await?;
+        stream.flush().await?;
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
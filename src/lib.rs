@@ -1,15 +1,88 @@
-use async_std::net::TcpListener;
+#[cfg(any(feature = "yaml", feature = "toml"))]
+pub mod config;
+mod connection;
+mod conversation;
+mod env;
+pub mod envelope;
+mod error;
+mod frame;
+#[cfg(feature = "graphql_ws")]
+pub mod graphql_ws;
+mod handle;
+mod handshake;
+mod http;
+pub mod matcher;
+mod response;
+mod scenario;
+mod snapshot;
+mod socket;
+mod stream;
+mod subscription;
+
+use async_lock::Semaphore;
+use async_std::channel::Receiver;
+use async_std::io::WriteExt;
+use async_std::net::{TcpListener, TcpStream};
 use async_std::task;
-use async_tungstenite::tungstenite::protocol::Message;
+use async_tungstenite::async_std::ConnectStream;
+use async_tungstenite::tungstenite::error::ProtocolError;
+use async_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse, Request, Response as HandshakeResponse,
+};
+use async_tungstenite::tungstenite::http::header::{SEC_WEBSOCKET_ACCEPT, WWW_AUTHENTICATE};
+use async_tungstenite::tungstenite::http::{HeaderMap, HeaderValue, StatusCode};
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use async_tungstenite::tungstenite::Error as WsError;
+use async_tungstenite::WebSocketStream;
+use futures_util::future::{self, BoxFuture, Either, FutureExt};
 use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use connection::{Connection, Tracked};
+pub use conversation::run_conversation;
+pub use env::MissingEnv;
+pub use envelope::{Envelopes, Response};
+pub use error::{ConfigError, SendError, WaitError};
+use frame::Pacing;
+pub use handle::{
+    ConnectionStats, Direction, DisconnectReason, Disconnection, LatencyStats, MatchLogEntry,
+    MatchedRule, Metrics, PendingSends, QueryParams, RecordedMessage, RecordedPing, RecordedPong,
+    ServerHandle, ShutdownCause, TranscriptEntry, UnsupportedFrame,
+};
+use handle::{Pushed, State, StatsRecorder};
+use handshake::{HeadTooLarge, RequestHead};
+use http::HttpRoute;
+pub use matcher::{HasPath, Matcher};
+pub use response::{ExhaustBehavior, MockResponse, Opcode, OpcodeBehavior, UnsupportedBehavior};
+pub use scenario::Scenario;
+use stream::{Memory, Replay, Transport};
+use subscription::Subscriptions;
 
 #[derive(Clone)]
 pub struct MockServerOptions {
     pub host: String,
     pub port: u16,
+    pub backlog: Option<u32>,
+    pub accept_delay: Option<Duration>,
+    pub concurrency_limit: Option<usize>,
+    pub tcp_keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
 }
 
 impl Default for MockServerOptions {
@@ -17,6 +90,12 @@ impl Default for MockServerOptions {
     /// Default values are:
     /// - host: "localhost"
     /// - port: 8080
+    /// - backlog: None (OS default)
+    /// - accept_delay: None
+    /// - concurrency_limit: None (unlimited)
+    /// - tcp_keepalive: None (OS default)
+    /// - recv_buffer_size: None (OS default)
+    /// - send_buffer_size: None (OS default)
     ///
     /// # Examples
     /// ```
@@ -28,13 +107,60 @@ impl Default for MockServerOptions {
         Self {
             host: "localhost".into(),
             port: 0,
+            backlog: None,
+            accept_delay: None,
+            concurrency_limit: None,
+            tcp_keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
         }
     }
 }
 
+/// A response sent to every message of the connections whose handshake
+/// query string contains the given key/value pair.
+#[derive(Clone)]
+struct QueryRule {
+    key: String,
+    value: String,
+    response: Value,
+}
+
+/// Callback called with the payload of every received pong.
+type PongCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+type ResponseKey = Arc<dyn Fn(&Request) -> String + Send + Sync>;
+type CountResponder = Arc<dyn Fn(usize) -> MockResponse + Send + Sync>;
+type Spawner = Arc<dyn Fn(BoxFuture<'static, ()>) + Send + Sync>;
+type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+type Transform = Arc<dyn Fn(Value) -> Value + Send + Sync>;
+type Inspector = Arc<dyn Fn(&Message) + Send + Sync>;
+type ShutdownCallback = Arc<dyn Fn(ShutdownCause) + Send + Sync>;
+type Shutdown<'a> = Pin<Box<dyn Future<Output = ShutdownCause> + Send + 'a>>;
+
+/// Closure producing a response asynchronously from a received message.
+type AsyncResponder =
+    Arc<dyn Fn(&Value) -> Pin<Box<dyn Future<Output = Value> + Send>> + Send + Sync>;
+
+/// Drop connections with the given probability before completing their handshake.
+#[derive(Clone, Copy)]
+struct Flaky {
+    drop_probability: f64,
+    seed: u64,
+}
+
 /// MockServer is a mock server that can be used to test your application.
 /// It can be used to test WebSocket connections.
 ///
+/// Servers are built from `MockServer::builder()`, the recommended entry
+/// point: chain the methods of the options to set on it, then `start` the
+/// server, or `bind` it first. Options are validated by `start` and
+/// `bind`, which fail with a `ConfigError` if they are inconsistent.
+/// `MockServer::default()` gives the same builder.
+///
+/// With the `tracing` feature, each connection is handled in a `connection`
+/// span carrying the peer address and connection index, with events for the
+/// handshake, each received message and the close.
+///
 /// # Examples
 /// ```
 /// use surimi::MockServer;
@@ -47,17 +173,17 @@ impl Default for MockServerOptions {
 ///
 /// # #[async_std::main]
 /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
-///     let (host, port) = MockServer::default()
+///     let server = MockServer::builder()
 ///         .responses(vec![
 ///             json!({"hello": "world"}),
 ///         ])
 ///         .start()
 ///         .await?;
 ///
-///     assert_eq!(host, "localhost");
-///     assert_ne!(port, 0); // the port should be pick randomly by the OS
+///     assert_eq!(server.host(), "localhost");
+///     assert_ne!(server.port(), 0); // the port should be pick randomly by the OS
 ///
-///     let endpoint = format!("ws://{}:{}", host, port);
+///     let endpoint = format!("ws://{}:{}", server.host(), server.port());
 ///     let (mut stream, _) = async_tungstenite::async_std::connect_async(endpoint).await?;
 ///     stream
 ///         .send(Message::Text("hello".into()))
@@ -74,21 +200,93 @@ impl Default for MockServerOptions {
 /// # }
 /// ```
 ///
+#[derive(Default)]
 pub struct MockServer {
-    pub responses: Vec<Value>,
+    /// Responses queued for each connection, in the order they are sent.
+    pub responses: Vec<MockResponse>,
     pub options: MockServerOptions,
+    query_rules: Vec<QueryRule>,
+    seq_responses: Option<(String, HashMap<u64, Value>)>,
+    http_routes: Vec<HttpRoute>,
+    response_headers: Vec<(String, String)>,
+    flaky: Option<Flaky>,
+    pending_sends: PendingSends,
+    on_pong: Option<PongCallback>,
+    respond_every: Option<usize>,
+    async_responder: Option<AsyncResponder>,
+    large_messages: Option<(usize, Value)>,
+    scenario: Option<Scenario>,
+    health_check: bool,
+    reset_after: Option<usize>,
+    abnormal_close_after: Option<usize>,
+    subscriptions: Vec<(String, Vec<Value>)>,
+    exhausted: ExhaustBehavior,
+    clock: Option<Clock>,
+    bandwidth_limit: Option<u64>,
+    write_stall: Option<(usize, Duration)>,
+    truncate_handshake: Option<usize>,
+    strict_control_frames: bool,
+    interpolate_env: bool,
+    missing_env: MissingEnv,
+    global_idle_timeout: Option<Duration>,
+    pong_delay: Option<Duration>,
+    invalid_response: Option<ConfigError>,
+    out_of_order: bool,
+    out_of_order_batch: Option<(usize, u64)>,
+    id_pointer: Option<String>,
+    lifetime: Option<Duration>,
+    on_shutdown: Option<ShutdownCallback>,
+    min_request_bytes: Option<usize>,
+    accumulate_undersized: bool,
+    responses_by: Option<(ResponseKey, HashMap<String, Vec<MockResponse>>)>,
+    require_version: Option<u8>,
+    close_delay: Option<Duration>,
+    total_count_responder: Option<CountResponder>,
+    redirect: Option<(u16, String)>,
+    redirect_count: Option<usize>,
+    split_json: Option<usize>,
+    spawner: Option<Spawner>,
+    rsv_bits: Option<u8>,
+    chaos_seed: Option<u64>,
+    pong_count: Option<usize>,
+    always_response: Option<MockResponse>,
+    reorder: Option<(usize, u64)>,
+    snapshot: Option<PathBuf>,
+    handshake_statuses: Vec<u16>,
+    response_drop_rate: Option<(f64, u64)>,
+    relay: bool,
+    relay_to_sender: bool,
+    preamble: Option<Vec<u8>>,
+    mask_server_frames: bool,
+    latency_ramp: Option<(Duration, Duration, Duration)>,
+    corrupt_accept_key: bool,
+    transcript_capacity: Option<usize>,
+    record_limit: Option<usize>,
+    alternate: Option<Vec<Value>>,
+    transform_response: Option<Transform>,
+    inspect_request: Option<Inspector>,
+    resumable: bool,
+    max_header_size: Option<usize>,
+    close_on_connect: Option<(CloseCode, String)>,
+    opcode_handlers: HashMap<Opcode, OpcodeBehavior>,
+    unsupported: UnsupportedBehavior,
+    matchers: Vec<Box<dyn Matcher + Send + Sync>>,
+    hang_handshake: bool,
+    basic_auth: Option<(String, String)>,
+    withhold_close_reply: bool,
+    captures: Vec<(String, String)>,
+    http_fallback_chunks: Option<Vec<String>>,
 }
 
-impl Default for MockServer {
-    fn default() -> Self {
-        Self {
-            responses: vec![],
-            options: MockServerOptions::default(),
-        }
+impl MockServer {
+    /// Builder of a server with the default options, e.g. listening on a
+    /// port picked by the OS, the recommended way to build a server: the
+    /// option methods chained on it configure the server, and `start`
+    /// serves it.
+    pub fn builder() -> Self {
+        Self::default()
     }
-}
 
-impl MockServer {
     pub fn host(mut self, host: String) -> Self {
         self.options.host = host;
         self
@@ -99,171 +297,5829 @@ impl MockServer {
         self
     }
 
-    pub fn responses(mut self, responses: Vec<Value>) -> Self {
-        let mut r = responses.clone();
-        r.reverse(); // handler use Vec.pop() to get the last response
-        self.responses = r;
+    /// Size of the listener's pending connections queue.
+    /// Connection attempts beyond it are refused or retried depending on the
+    /// OS: Linux silently drops the SYN so the client retries, while other
+    /// systems may refuse the connection. The OS may also cap the value
+    /// (e.g. `net.core.somaxconn` on Linux).
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.options.backlog = Some(backlog);
         self
     }
 
-    pub async fn start(self) -> Result<(String, u16), Box<dyn Error>> {
-        let listener =
-            TcpListener::bind(format!("{}:{}", &self.options.host, &self.options.port)).await?;
+    /// Wait for `delay` before accepting any connection.
+    /// Unlike a handshake delay, connections are not even accepted yet: early
+    /// connection attempts sit in the OS backlog until the server starts
+    /// accepting. Combine it with `backlog` to test queued vs refused
+    /// connection attempts.
+    pub fn accept_delay(mut self, delay: Duration) -> Self {
+        self.options.accept_delay = Some(delay);
+        self
+    }
 
-        let port = listener.local_addr()?.port();
-        let host = String::from(&self.options.host);
+    /// Handle at most `n` connections at once.
+    /// Each accepted connection takes a permit of a semaphore of `n` permits
+    /// before its handshake, and gives it back when it ends. Unlike a hard
+    /// cap on connections, excess connections are not refused: they are
+    /// accepted but wait for a permit, in the order they were accepted.
+    pub fn concurrency_limit(mut self, n: usize) -> Self {
+        self.options.concurrency_limit = Some(n);
+        self
+    }
 
-        task::spawn(async move {
-            self.ws_handler(&listener).await.unwrap();
-        });
+    /// Enable TCP keepalive on accepted sockets, probing idle peers after
+    /// `time`, so dead peers of half-open connections are detected by the
+    /// OS. `None` keeps the OS default, usually no keepalive.
+    /// The probe interval and count are left to the OS defaults, and some
+    /// platforms (e.g. OpenBSD) only support a system-wide idle time.
+    pub fn tcp_keepalive(mut self, time: Option<Duration>) -> Self {
+        self.options.tcp_keepalive = time;
+        self
+    }
 
-        Ok((host, port))
+    /// Set the size of the receive buffer (`SO_RCVBUF`) of accepted
+    /// sockets to `size` bytes: larger buffers reduce the syscalls reading
+    /// large messages, smaller ones make the client block sooner, to test
+    /// its backpressure handling. By default the OS default is kept.
+    /// The OS may adjust or clamp the size, e.g. Linux doubles it for its
+    /// bookkeeping and caps it to `net.core.rmem_max`: the effective sizes
+    /// are recorded by `ServerHandle::buffer_sizes`.
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.options.recv_buffer_size = Some(size);
+        self
     }
 
-    async fn ws_handler(self, listener: &TcpListener) -> Result<(), Box<dyn Error>> {
-        let mut incoming = listener.incoming();
-        while let Some(stream) = incoming.next().await {
-            let stream = stream?;
-            let mut socket = async_tungstenite::accept_async(stream).await?;
-            let mut responses = self.responses.clone();
-
-            while let Some(message) = socket.next().await {
-                match message? {
-                    Message::Text(_) => {
-                        if let Some(response) = responses.pop() {
-                            socket.send(Message::Text(response.to_string())).await?;
-                            continue;
-                        }
-                        socket
-                            .send(Message::Text("No more response".into()))
-                            .await?;
-                    }
-                    Message::Close(_) => break,
-                    _ => {}
+    /// Set the size of the send buffer (`SO_SNDBUF`) of accepted sockets to
+    /// `size` bytes, like `recv_buffer_size`: smaller buffers make sending
+    /// responses wait sooner for a client slow to read them. On Linux, the
+    /// size is capped to `net.core.wmem_max`.
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Queue the responses sent, in order, to the text messages of each
+    /// connection. Responses can be JSON `Value`s or any `MockResponse`.
+    pub fn responses<R: Into<MockResponse>>(mut self, responses: Vec<R>) -> Self {
+        self.responses = responses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Responses queued with `responses` or one of its variants, in the
+    /// order they are sent, to check the setup before starting the server,
+    /// e.g. when it is loaded from a file.
+    pub fn configured_responses(&self) -> &[MockResponse] {
+        &self.responses
+    }
+
+    /// Queue responses each sent `count` times in a row before moving to the
+    /// next one, e.g. to answer the first polls with "pending" and the next
+    /// one with "done". A count of zero skips its response.
+    pub fn responses_repeated(self, responses: Vec<(usize, Value)>) -> Self {
+        self.responses(
+            responses
+                .into_iter()
+                .flat_map(|(count, response)| std::iter::repeat_n(response, count))
+                .collect(),
+        )
+    }
+
+    /// Queue responses each made of several JSON values coalesced in a
+    /// single text frame, joined with `separator` (e.g. `"\n"` for
+    /// newline-delimited JSON), to test clients which must split frames
+    /// themselves. Each group is sent as one frame in answer to one message,
+    /// where a server sending its values as separate messages would use one
+    /// frame per value. An empty group sends an empty frame.
+    pub fn batch_responses(self, groups: Vec<Vec<Value>>, separator: &str) -> Self {
+        self.responses(
+            groups
+                .into_iter()
+                .map(|group| {
+                    let values: Vec<_> = group.iter().map(Value::to_string).collect();
+                    MockResponse::Text(values.join(separator))
+                })
+                .collect(),
+        )
+    }
+
+    /// Queue responses given as JSON strings, e.g. pasted from real API
+    /// captures, like `responses` does with `Value`s.
+    /// Each string is parsed as JSON, so its whitespace and formatting are
+    /// normalized. `start` returns a `ConfigError::InvalidJson` with the
+    /// index of the first string which is not valid JSON.
+    pub fn responses_str(mut self, responses: Vec<&str>) -> Self {
+        let mut values = Vec::with_capacity(responses.len());
+        for (index, response) in responses.into_iter().enumerate() {
+            match serde_json::from_str::<Value>(response) {
+                Ok(value) => values.push(value),
+                Err(error) => {
+                    self.invalid_response = Some(ConfigError::InvalidJson {
+                        index,
+                        error: error.to_string(),
+                    });
+                    return self;
                 }
             }
         }
-        Ok(())
+        self.invalid_response = None;
+        self.responses(values)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+    /// Queue different responses per client, e.g. per tenant of a
+    /// multi-tenant API. `key` computes the key of each connection from its
+    /// handshake request (e.g. from a header or a query parameter), and the
+    /// connection is answered with the responses `map` has for this key.
+    /// Connections whose key is not in `map` get the `responses` queue.
+    pub fn responses_by<F>(mut self, key: F, map: HashMap<String, Vec<Value>>) -> Self
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        let map = map
+            .into_iter()
+            .map(|(key, responses)| (key, responses.into_iter().map(Into::into).collect()))
+            .collect();
+        self.responses_by = Some((Arc::new(key), map));
+        self
+    }
 
-    fn endpoint(host: &str, port: u16) -> String {
-        format!("ws://{}:{}", host, port)
+    /// Let clients resume their session when they reconnect, like servers
+    /// supporting resume tokens. A client names its session with the
+    /// `session` query parameter of its handshake (e.g.
+    /// `ws://host/?session=abc`): a connection resuming a known session
+    /// picks up the responses queue where the previous connections of the
+    /// session left off instead of starting over, while an unknown session
+    /// id starts a fresh session with the whole queue. Connections without
+    /// a session id are served as usual.
+    /// Sessions are tracked by `ServerHandle::active_sessions` and
+    /// `ServerHandle::sessions`.
+    pub fn resumable(mut self, resumable: bool) -> Self {
+        self.resumable = resumable;
+        self
     }
 
-    #[async_std::test]
-    #[should_panic]
-    async fn should_panic_if_same_port_used_twice() {
-        let (_, _) = MockServer::default().port(8080).start().await.unwrap();
-        let (_, _) = MockServer::default().port(8080).start().await.unwrap();
+    /// Script a conversation made of `(request, response)` pairs: the
+    /// responses are queued in order, as with `responses`.
+    /// Use `run_conversation` on the client side to send the requests and
+    /// assert each response.
+    pub fn expect_conversation(self, conversation: Vec<(Value, Value)>) -> Self {
+        self.responses(
+            conversation
+                .into_iter()
+                .map(|(_, response)| response)
+                .collect(),
+        )
     }
 
-    #[async_std::test]
-    async fn connect() -> Result<(), Box<dyn Error>> {
-        let (host, port) = MockServer::default().start().await?;
+    /// Answer every message of a connection with `response` when the client
+    /// sent `key=value` in its handshake query string (e.g. `ws://host/?token=abc`).
+    /// Values are compared URL-decoded and a key sent several times matches
+    /// if any of its values does. Rules are checked in the order they were
+    /// added, connections matching none of them use the responses queue.
+    pub fn when_query(mut self, key: String, value: String, response: Value) -> Self {
+        self.query_rules.push(QueryRule {
+            key,
+            value,
+            response,
+        });
+        self
+    }
 
-        assert_eq!(host, "localhost");
-        assert_ne!(port, 0); // the port should be pick randomly by the OS
+    /// Answer text messages holding a value at the JSON `pointer` (RFC 6901)
+    /// with `response`, whatever the value, e.g. `/token` for messages with
+    /// a top-level `token` field. A `null` value counts as present: only
+    /// messages where the pointer resolves to nothing, or which are not
+    /// JSON objects or arrays, do not match.
+    /// The rule is registered as a `HasPath` matcher, see `add_matcher` for
+    /// its precedence and how its matches are logged.
+    pub fn when_has_path(self, pointer: &str, response: Value) -> Self {
+        self.add_matcher(Box::new(HasPath::new(pointer, response)))
+    }
 
-        let (mut stream, _) =
-            async_tungstenite::async_std::connect_async(endpoint(&host, port)).await?;
+    /// Register `matcher` to answer the text and binary messages it
+    /// matches, with custom routing logic, see the `matcher` module.
+    /// Matchers, `when_has_path` rules included, are tried in the order
+    /// they were registered, and the first one returning a response answers
+    /// the message. For text messages, they come after the subscription
+    /// controls, `respond_every` and `scenario` rules, and before the
+    /// `respond_by_seq` responses, `when_query` rules, responders and the
+    /// responses queue. Binary messages no matcher answers are answered by
+    /// `always_respond`, if set.
+    /// Matches are logged as `MatchedRule::Matcher` with the index of the
+    /// matcher in registration order.
+    pub fn add_matcher(mut self, matcher: Box<dyn Matcher + Send + Sync>) -> Self {
+        self.matchers.push(matcher);
+        self
+    }
 
-        stream.close(None).await?;
-        Ok(())
+    /// Answer text messages numbered with a sequence number with the
+    /// response mapped to their number, for protocols correlating responses
+    /// to requests with a `seq` field. The sequence number is read at the
+    /// JSON `pointer` (RFC 6901), e.g. `/seq`, and must be an unsigned
+    /// integer. It is echoed into the response at the same pointer: it
+    /// replaces the value there, or is added as a field if the pointer is a
+    /// single field missing from the response object, e.g.
+    /// `{"seq": 7, "result": "ok"}` for `{"result": "ok"}`.
+    /// Messages without a sequence number, with a non-numeric one or with
+    /// one missing from `responses` fall back to the next matchers: they
+    /// come after the `when_has_path` rules and before the `when_query`
+    /// ones, responders and the responses queue.
+    pub fn respond_by_seq(mut self, pointer: &str, responses: HashMap<u64, Value>) -> Self {
+        self.seq_responses = Some((pointer.into(), responses));
+        self
     }
 
-    #[async_std::test]
-    async fn connect_with_custom_config() -> Result<(), Box<dyn Error>> {
-        let (host, port) = MockServer::default()
-            .host("127.0.0.1".into())
-            .port(8080)
-            .start()
-            .await?;
+    /// Serve a canned HTTP response to plain HTTP GET requests on `path`
+    /// (e.g. a health-check endpoint), without upgrading the connection.
+    /// WebSocket upgrade requests are still handled normally whatever their path,
+    /// and plain HTTP requests matching no route get a 404.
+    pub fn http_route(
+        mut self,
+        path: String,
+        status: u16,
+        body: String,
+        content_type: String,
+    ) -> Self {
+        self.http_routes.push(HttpRoute {
+            path,
+            status,
+            body,
+            content_type,
+        });
+        self
+    }
 
-        assert_eq!(host, "127.0.0.1");
-        assert_eq!(port, 8080);
+    /// Answer plain HTTP requests matching no `http_route`, whatever their
+    /// method, with a 200 OK whose body streams the `body_chunks`, like the
+    /// long-polling endpoint of a client falling back to HTTP when
+    /// WebSocket fails. The body is sent with `Transfer-Encoding: chunked`,
+    /// each chunk framed as its size in hexadecimal, CRLF, the chunk and
+    /// CRLF, and flushed on its own, then the body is ended by a chunk of
+    /// size 0. Empty chunks are skipped, as they would end the body early.
+    /// WebSocket upgrade requests are never answered with it.
+    pub fn http_fallback_chunked(mut self, body_chunks: Vec<String>) -> Self {
+        self.http_fallback_chunks = Some(body_chunks);
+        self
+    }
 
-        let (mut stream, _) =
-            async_tungstenite::async_std::connect_async(endpoint(&host, port)).await?;
+    /// Answer plain HTTP GET requests on `/healthz` with a 200 OK, or a
+    /// 503 Service Unavailable once the server is draining (see
+    /// `ServerHandle::drain`), so orchestrators and wait-for-it scripts can
+    /// probe the server. It takes precedence over `http_route`.
+    pub fn health_check(mut self, enabled: bool) -> Self {
+        self.health_check = enabled;
+        self
+    }
 
-        stream.close(None).await?;
-        Ok(())
+    /// Add headers to the 101 Switching Protocols response of the handshake
+    /// (e.g. `X-Server-Version`).
+    /// Headers required by the handshake (`Upgrade`, `Connection` and
+    /// `Sec-WebSocket-Accept`) cannot be overridden: `start` returns a
+    /// `ConfigError` if one of them is given.
+    pub fn response_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.response_headers = headers;
+        self
     }
 
-    #[async_std::test]
-    async fn should_answer_pong() -> Result<(), Box<dyn Error>> {
-        let (host, port) = MockServer::default().start().await?;
+    /// Randomly drop connections before completing their WebSocket handshake,
+    /// each one with a `drop_probability` chance (between 0 and 1), so the
+    /// client sees a handshake failure and can exercise its reconnection logic.
+    /// Draws come from an RNG seeded with `seed`, so the same sequence of
+    /// connections is dropped on every run.
+    /// Plain HTTP requests are never dropped.
+    /// Dropped and served connections are counted by the `ServerHandle`.
+    pub fn flaky(mut self, drop_probability: f64, seed: u64) -> Self {
+        self.flaky = Some(Flaky {
+            drop_probability,
+            seed,
+        });
+        self
+    }
 
-        let (mut stream, _) =
-            async_tungstenite::async_std::connect_async(endpoint(&host, port)).await?;
+    /// Relay the text and binary messages of each client to every other
+    /// connected client, like a signaling or chat server, instead of
+    /// answering them: when client A sends a message, clients B and C
+    /// receive it. Messages are relayed to the clients connected when they
+    /// are received: a client disconnecting meanwhile misses them, which is
+    /// counted by `ServerHandle::failed_deliveries`. Relayed messages are
+    /// forwarded unchanged, without the transformations of the responses.
+    /// Connected clients are counted by `ServerHandle::connected_clients`.
+    pub fn relay(mut self, enabled: bool) -> Self {
+        self.relay = enabled;
+        self
+    }
 
-        stream.send(Message::Ping("Some request".into())).await?;
+    /// Relay the messages of `relay` to their sender too, which does not
+    /// receive its own messages by default.
+    pub fn relay_to_sender(mut self, enabled: bool) -> Self {
+        self.relay_to_sender = enabled;
+        self
+    }
 
-        let response = stream.next().await.unwrap()?;
-        assert_eq!(
-            response,
-            Message::Pong("Some request".into()),
-            "should answer with pong"
-        );
+    /// Leave each received text or binary message unanswered with a
+    /// `probability` chance (between 0 and 1), as if its response was lost,
+    /// to test client timeouts and retries. Unlike `flaky`, which drops
+    /// whole connections before their handshake, the connection stays open
+    /// and only single responses are lost.
+    /// The message is still recorded, but not processed: it does not
+    /// consume a queued response, so a retried request gets the response
+    /// the lost one would have. Draws come from an RNG seeded with `seed`
+    /// for each connection, so the same messages are dropped on every run.
+    /// Dropped responses are counted by `ServerHandle::dropped_responses`.
+    pub fn response_drop_rate(mut self, probability: f64, seed: u64) -> Self {
+        self.response_drop_rate = Some((probability, seed));
+        self
+    }
 
-        stream.close(None).await?;
-        Ok(())
+    /// Choose what `ServerHandle::send` does when no client is connected:
+    /// buffer the messages for the next connecting client (default) or
+    /// reject them with an error.
+    pub fn pending_sends(mut self, pending_sends: PendingSends) -> Self {
+        self.pending_sends = pending_sends;
+        self
     }
 
-    #[async_std::test]
-    async fn should_wait_for_close_message() -> Result<(), Box<dyn Error>> {
-        let (host, port) = MockServer::default().start().await?;
+    /// Call `callback` with the payload of every pong frame received.
+    /// Pongs are recorded by the `ServerHandle` whether a callback is set or not.
+    pub fn on_pong<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.on_pong = Some(Arc::new(callback));
+        self
+    }
 
-        let (mut stream, _) =
-            async_tungstenite::async_std::connect_async(endpoint(&host, port)).await?;
+    /// Only answer every `n`th text message of a connection and stay silent
+    /// otherwise, like a server batching or coalescing requests.
+    /// Every message is still recorded. `n == 1` answers every message, and
+    /// `start` returns a `ConfigError` if `n == 0`.
+    pub fn respond_every(mut self, n: usize) -> Self {
+        self.respond_every = Some(n);
+        self
+    }
 
-        for _ in 0..10 {
-            stream.send(Message::Text("Some request".into())).await?;
-            let response = stream.next().await.unwrap()?;
-            assert_eq!(response, Message::Text("No more response".into()),);
-        }
+    /// Answer every text message with the value produced by the future
+    /// `responder` returns, e.g. to read the response from a channel or a
+    /// database stub when the message is received.
+    /// The closure receives the message parsed as JSON, or as a JSON string
+    /// if it is not valid JSON. It takes precedence over the responses queue
+    /// but not over `when_query` rules.
+    /// Messages of a connection are handled one at a time: while a future is
+    /// pending, the next messages wait and their responses are sent in the
+    /// order the messages were received.
+    pub fn async_respond<F>(mut self, responder: F) -> Self
+    where
+        F: Fn(&Value) -> Pin<Box<dyn Future<Output = Value> + Send>> + Send + Sync + 'static,
+    {
+        self.async_responder = Some(Arc::new(responder));
+        self
+    }
 
-        stream.close(None).await?;
-        Ok(())
+    /// Answer every text message with the response `responder` produces
+    /// from the number of text messages the server received so far, on all
+    /// its connections and including this one, e.g. to mock a server which
+    /// degrades as its overall traffic grows.
+    /// The count is shared by all connections and only grows over the
+    /// lifetime of the server, but connections are served concurrently so
+    /// `responder` may be called from several threads at once, and the
+    /// order of their counts only follows the order in which the server
+    /// handled the messages. It takes precedence over the responses queue
+    /// but not over `async_respond`.
+    pub fn respond_by_total_count<F>(mut self, responder: F) -> Self
+    where
+        F: Fn(usize) -> MockResponse + Send + Sync + 'static,
+    {
+        self.total_count_responder = Some(Arc::new(responder));
+        self
     }
 
-    #[async_std::test]
-    async fn connect_and_answer() -> Result<(), Box<dyn Error>> {
-        let mocked_responses = vec![
-            json!({"hello": "world"}),
-            json!({"hello": "france"}),
-            json!({"hello": "montpellier"}),
-        ];
+    /// Answer the text messages of a connection with `responses` in turn,
+    /// by index: the `i`th text message of a connection it answers, counting
+    /// from 0, gets `responses[i % responses.len()]`, e.g. even messages get
+    /// the first of two responses and odd ones the second. Messages skipped
+    /// by `respond_every` or answered by another source are not counted.
+    /// Unlike the responses queue, which is drained and then exhausted,
+    /// the list never exhausts: it wraps around forever, whatever the
+    /// messages contain. It takes precedence over the responses queue but
+    /// not over `respond_by_total_count`, and `start` returns a
+    /// `ConfigError` if `responses` is empty.
+    pub fn alternate(mut self, responses: Vec<Value>) -> Self {
+        self.alternate = Some(responses);
+        self
+    }
 
-        let (host, port) = MockServer::default()
-            .responses(mocked_responses.clone())
-            .start()
-            .await?;
+    /// Answer text and binary messages larger than `max` bytes with `error`
+    /// instead of a queued response, keeping the connection open.
+    /// Unlike a protocol-level message size limit, the client gets an
+    /// application-level error it can handle. Oversized messages are still
+    /// recorded, and counted by `ServerHandle::oversized_messages`.
+    pub fn reject_large_messages(mut self, max: usize, error: Value) -> Self {
+        self.large_messages = Some((max, error));
+        self
+    }
 
-        assert_eq!(host, "localhost");
-        assert_ne!(port, 0); // the port should be pick randomly by the OS
+    /// Reset the connection with a TCP RST after sending `n` queued
+    /// responses, instead of closing it cleanly, so the client observes a
+    /// connection reset error.
+    /// Reset connections are recorded with `DisconnectReason::Reset`.
+    pub fn reset_after(mut self, n: usize) -> Self {
+        self.reset_after = Some(n);
+        self
+    }
 
-        let (mut stream, _) =
-            async_tungstenite::async_std::connect_async(endpoint(&host, port)).await?;
+    /// Close every connection right after its handshake completes, with a
+    /// close frame of the given `code` and `reason`, without answering nor
+    /// recording the messages the client sends, like a server shutting down
+    /// gracefully while a client connects. Unlike a rejected handshake, the
+    /// client sees the connection established then immediately closed.
+    /// The close frame sent is recorded by `ServerHandle::connect_close_frame`.
+    pub fn close_on_connect(mut self, code: CloseCode, reason: &str) -> Self {
+        self.close_on_connect = Some((code, reason.into()));
+        self
+    }
 
-        for m_response in mocked_responses {
-            stream
-                .send(Message::Text(String::from("Trigger mocked server reply")))
-                .await?;
+    /// Drop the connection without a close handshake after sending `n`
+    /// queued responses, so the client synthesizes a 1006 abnormal closure
+    /// code, which is never sent on the wire.
+    /// Unlike `reset_after`, the TCP connection is closed gracefully with a
+    /// FIN: the client reads the end of the stream in the middle of the
+    /// WebSocket session, while a RST makes its reads fail with a
+    /// connection reset error. WebSocket clients usually report both as
+    /// 1006, but only a FIN lets them read the data sent before it.
+    /// Dropped connections are recorded with `DisconnectReason::Abnormal`.
+    pub fn abnormal_close_after(mut self, n: usize) -> Self {
+        self.abnormal_close_after = Some(n);
+        self
+    }
 
-            let response: Value =
-                serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+    /// Stream `responses` to clients subscribing to `id`, for pub/sub
+    /// protocols multiplexing several subscriptions on one connection.
+    /// A client subscribes by sending `{"subscribe": id}` and unsubscribes
+    /// with `{"unsubscribe": id}`; those messages are not answered.
+    /// Each response is sent as `{"subscription": id, "data": response}`,
+    /// and the responses of the active subscriptions are interleaved.
+    /// Active subscriptions are exposed by `ServerHandle::active_subscriptions`.
+    pub fn subscription(mut self, id: String, responses: Vec<Value>) -> Self {
+        self.subscriptions.push((id, responses));
+        self
+    }
 
-            assert_eq!(response, m_response);
-            assert_eq!(response["hello"], m_response["hello"]);
+    /// Choose what the server does with text messages received once the
+    /// responses are exhausted. Defaults to
+    /// `ExhaustBehavior::SendText("No more response")`.
+    pub fn on_exhausted(mut self, behavior: ExhaustBehavior) -> Self {
+        self.exhausted = behavior;
+        self
+    }
+
+    /// Choose how the server handles the messages of each opcode, instead
+    /// of its built-in handling. Opcodes missing from `handlers`, or mapped
+    /// to `OpcodeBehavior::Default`, are handled as usual:
+    ///
+    /// - `Text`: answered by the matchers and the responses queue.
+    /// - `Binary`: answered by `always_respond` only.
+    /// - `Ping`: answered with a pong, see `pong_delay` and `pong_count`.
+    /// - `Pong`: recorded and passed to `on_pong`.
+    /// - `Close`: answered with the close reply, ending the connection.
+    ///
+    /// `OpcodeBehavior::Respond` answers every message of the opcode with
+    /// its response and `OpcodeBehavior::Ignore` does not answer them: a
+    /// ping gets no pong, a pong is neither recorded nor passed to
+    /// `on_pong`, and a close frame gets no close reply, the connection
+    /// being dropped. Both take precedence over every matcher and the
+    /// responses queue, which are not consumed, but come after
+    /// `reject_large_messages`, `relay` and `response_drop_rate`. Text and
+    /// binary messages are still recorded, and pings still recorded as
+    /// unanswered. `start` returns a `ConfigError` if close frames are
+    /// given a response, as nothing can be sent once the client closed.
+    pub fn opcode_handlers(mut self, handlers: HashMap<Opcode, OpcodeBehavior>) -> Self {
+        self.opcode_handlers = handlers;
+        self
+    }
+
+    /// Choose what the server does with frames of the opcodes the WebSocket
+    /// protocol reserves for future use: 3 to 7 for data frames and 11 to
+    /// 15 for control frames. They are the only unsupported ones, as every
+    /// other opcode is handled by the server, see `opcode_handlers`.
+    /// tungstenite rejects them with a protocol error, which ends the
+    /// connection by default, with `UnsupportedBehavior::Fail`. With the
+    /// other behaviors, the server drops these frames before tungstenite
+    /// reads them, so it can serve the connection on, and reacts to each
+    /// one once the frames received along with it are handled.
+    /// Unsupported frames are recorded by `ServerHandle::unsupported_frames`
+    /// whatever the behavior, to check a client never sends them.
+    pub fn on_unsupported(mut self, behavior: UnsupportedBehavior) -> Self {
+        self.unsupported = behavior;
+        self
+    }
+
+    /// Answer every message with `response`, indefinitely, for mocks which
+    /// do not need a queue. Text messages are answered with the queued
+    /// `responses` first if any, then with `response` instead of the
+    /// `on_exhausted` behavior, so with an empty queue every text message
+    /// gets `response`. Binary messages, which the queue does not answer,
+    /// get `response` too.
+    pub fn always_respond(mut self, response: impl Into<MockResponse>) -> Self {
+        self.always_response = Some(response.into());
+        self
+    }
+
+    /// Queue `bytes` as the only response, written as is on the connection
+    /// instead of a WebSocket frame, e.g. a hand-crafted frame whose length
+    /// header does not match its payload, to test how a client parser
+    /// handles malformed framing. Like `responses`, it replaces the queued
+    /// responses: queue `MockResponse::Raw` with `responses` to mix raw
+    /// bytes with other responses.
+    /// This is an expert negative-testing tool: the bytes are not validated
+    /// and go straight to the wire, bypassing the WebSocket codec, so they
+    /// are not paced, masked, compressed nor given reserved bits, and an
+    /// invalid frame usually makes the client fail the connection. Written
+    /// bytes are recorded by `ServerHandle::raw_responses`.
+    pub fn raw_bytes_response(mut self, bytes: Vec<u8>) -> Self {
+        self.responses = vec![MockResponse::Raw(bytes)];
+        self
+    }
+
+    /// Apply `transform` to the JSON value of every response just before it
+    /// is serialized, e.g. to add a `"server": "surimi"` field to all of
+    /// them or to wrap them in an envelope, without changing each queued
+    /// response. It applies to the JSON responses of every source: the
+    /// queue, the matchers and responders, `always_respond`, pushed
+    /// messages, subscriptions and the `reject_large_messages` error, after
+    /// their `{now}` placeholders and environment variables are replaced.
+    /// Text and binary responses are not JSON values and are sent
+    /// unchanged, like the text answers of `on_exhausted`.
+    pub fn transform_response<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        self.transform_response = Some(Arc::new(transform));
+        self
+    }
+
+    /// Call `inspector` with every message received, to run side effects
+    /// such as custom metrics, logging or assertions inline, while the
+    /// messages are recorded anyway. It only observes the messages: it
+    /// cannot change them nor answer them.
+    /// It is called with the raw message as soon as it is received, before
+    /// it is recorded, matched and answered, for every kind of message,
+    /// including ping, pong and close frames.
+    pub fn inspect_request<F>(mut self, inspector: F) -> Self
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.inspect_request = Some(Arc::new(inspector));
+        self
+    }
+
+    /// Use `clock` instead of the system time to stamp responses.
+    /// Every `{now}` placeholder found in a response, in its text or in the
+    /// strings of its JSON value, is replaced by the time given by the
+    /// clock when the response is sent, formatted as RFC 3339 with
+    /// milliseconds, e.g. `2021-10-01T12:00:00.000Z`. A fixed clock makes
+    /// timestamped responses deterministic.
+    pub fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Throttle responses to about `bytes_per_sec` bytes per second, to
+    /// simulate a slow link: their frames are written in small chunks with
+    /// pauses in between. The throttling is approximate, and applies to each
+    /// connection independently.
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Stall for `duration` in the middle of each response, once
+    /// `after_bytes` of its frame (header included) were written, to test
+    /// how clients handle a partial message and a server whose send buffer
+    /// is blocked. Unlike `bandwidth_limit` which writes at a steady pace,
+    /// the frame is written at once around a single long pause, and it
+    /// takes precedence over it. Smaller responses are written at once.
+    pub fn write_stall(mut self, after_bytes: usize, duration: Duration) -> Self {
+        self.write_stall = Some((after_bytes, duration));
+        self
+    }
+
+    /// Send each JSON response split across `chunks` text messages, to
+    /// test clients which incrementally parse JSON streams. This produces
+    /// distinct, complete text messages, each carrying a piece of the
+    /// serialized JSON which is not valid JSON on its own, and not a single
+    /// message fragmented in continuation frames. Responses serialized in
+    /// fewer characters than `chunks` are sent in one message per
+    /// character. `start` returns a `ConfigError` if `chunks == 0`.
+    pub fn split_json(mut self, chunks: usize) -> Self {
+        self.split_json = Some(chunks);
+        self
+    }
+
+    /// Spawn the background tasks of the server, the accept loop and one
+    /// task per connection, with `spawner` instead of
+    /// `async_std::task::spawn`, e.g. to run them on another executor.
+    /// `spawner` must run the futures it is given to completion, and may
+    /// be called from any thread: the futures are `Send` and `'static`.
+    /// Sockets and timers still rely on the async-std reactor, which runs
+    /// in its own thread and works with any executor.
+    pub fn spawner<F>(mut self, spawner: F) -> Self
+    where
+        F: Fn(BoxFuture<'static, ()>) + Send + Sync + 'static,
+    {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Set the reserved bits `bits` in the frames of every response, to
+    /// test that clients fail connections receiving unexpected reserved
+    /// bits. The bits are given as in the frame header: `0b100` is RSV1,
+    /// `0b010` RSV2 and `0b001` RSV3. This deliberately violates the
+    /// protocol, unless an extension negotiated with the client uses those
+    /// bits. Frames sent with reserved bits are recorded by
+    /// `ServerHandle::rsv_frames`. `start` returns a `ConfigError` if
+    /// `bits` is over `0b111`.
+    pub fn set_rsv(mut self, bits: u8) -> Self {
+        self.rsv_bits = Some(bits);
+        self
+    }
+
+    /// Delay each response by a latency increasing linearly from `start` to
+    /// `end` over the `over` duration since the server started, like a
+    /// degrading server, to test client timeout thresholds and circuit
+    /// breakers. Once `over` elapsed, the latency stays at `end`. `end` can
+    /// be lower than `start` to model a recovering server. The answers of
+    /// `on_exhausted` are not delayed.
+    /// The delay applied to each response is recorded by
+    /// `ServerHandle::response_delays`.
+    pub fn latency_ramp(mut self, start: Duration, end: Duration, over: Duration) -> Self {
+        self.latency_ramp = Some((start, end, over));
+        self
+    }
+
+    /// Mask the frames of the responses with a random masking key, like a
+    /// client does. This deliberately violates RFC 6455, which forbids
+    /// servers to mask their frames, to check clients reject them with a
+    /// protocol error. Control frames (pongs and close frames) are not
+    /// masked. Masked frames are counted by `ServerHandle::masked_frames`.
+    pub fn mask_server_frames(mut self, enabled: bool) -> Self {
+        self.mask_server_frames = enabled;
+        self
+    }
+
+    /// RNG of the given connection, seeded by `chaos_seed` if set, or with
+    /// `seed` otherwise.
+    fn rng(&self, seed: u64, connection: usize) -> StdRng {
+        match self.chaos_seed {
+            Some(chaos_seed) => chaos_rng(chaos_seed, connection),
+            None => StdRng::seed_from_u64(seed),
         }
+    }
 
-        stream.close(None).await?;
+    fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        match &self.spawner {
+            Some(spawner) => spawner(Box::pin(future)),
+            None => {
+                task::spawn(future);
+            }
+        }
+    }
+
+    /// Answer handshakes with a `Sec-WebSocket-Accept` header which does not
+    /// match the `Sec-WebSocket-Key` of the client. This deliberately
+    /// violates RFC 6455, to check clients verify the accept key and fail
+    /// the handshake. Corrupted handshakes are counted by
+    /// `ServerHandle::corrupted_accept_keys`.
+    pub fn corrupt_accept_key(mut self, enabled: bool) -> Self {
+        self.corrupt_accept_key = enabled;
+        self
+    }
+
+    /// Write `bytes` on the connection before the handshake response, like
+    /// a misbehaving proxy sending an `HTTP/1.0 200 OK` before the actual
+    /// `101 Switching Protocols`, to test how the handshake parser of a
+    /// client handles garbage ahead of the response. This breaks compliant
+    /// clients on purpose: they are expected to reject the handshake.
+    /// Connections sent the preamble are counted by
+    /// `ServerHandle::sent_preambles`.
+    pub fn preamble(mut self, bytes: Vec<u8>) -> Self {
+        self.preamble = Some(bytes);
+        self
+    }
+
+    /// Write only the first `bytes` of the 101 Switching Protocols response
+    /// to WebSocket upgrade requests, then close the connection, so the
+    /// client sees an incomplete handshake.
+    /// This deliberately violates the protocol, to test how robustly clients
+    /// parse the handshake response. Truncated handshakes are counted by
+    /// `ServerHandle::truncated_handshakes`.
+    pub fn truncate_handshake(mut self, bytes: usize) -> Self {
+        self.truncate_handshake = Some(bytes);
+        self
+    }
+
+    /// Read the handshake request of WebSocket upgrades but never answer
+    /// it, to test client connect timeouts: unlike a delayed handshake, the
+    /// client waits until its own timeout, on a connection kept open until
+    /// it closes it. Each connection is served by its own task, so hung
+    /// handshakes do not hold back the other connections.
+    /// Hung handshakes are counted by `ServerHandle::hung_handshakes`.
+    pub fn hang_handshake(mut self, enabled: bool) -> Self {
+        self.hang_handshake = enabled;
+        self
+    }
+
+    /// Close connections with a 1002 protocol error close frame when the
+    /// client sends a control frame (ping, pong or close) violating the
+    /// spec: with a payload over 125 bytes, or fragmented.
+    /// Violations are counted by `ServerHandle::control_frame_violations`.
+    /// Disabled by default: such connections are then dropped without a
+    /// close frame.
+    pub fn strict_control_frames(mut self, enabled: bool) -> Self {
+        self.strict_control_frames = enabled;
+        self
+    }
+
+    /// Replace `${VAR}` tokens in responses with the value of the environment
+    /// variable `VAR` when they are sent, so one fixture adapts to different
+    /// environments. Tokens are looked up in the text of the response, or in
+    /// the strings of its JSON value; `$${` is an escaped, literal `${`.
+    /// Tokens of variables which are not set are kept as is, unless
+    /// `missing_env` says otherwise.
+    pub fn interpolate_env(mut self, enabled: bool) -> Self {
+        self.interpolate_env = enabled;
+        self
+    }
+
+    /// Choose what `interpolate_env` does with tokens of variables which are
+    /// not set. Defaults to `MissingEnv::Keep`.
+    pub fn missing_env(mut self, missing: MissingEnv) -> Self {
+        self.missing_env = missing;
+        self
+    }
+
+    /// Close every connection and stop accepting new ones if no message is
+    /// received, on any connection, for `timeout`. The timer starts when the
+    /// server starts and is reset by every message received.
+    /// It is a whole-server watchdog catching tests that connect to the mock
+    /// but hang without exercising it. Connections are closed with a 1001
+    /// going away close frame, and `ServerHandle::idle_timeout_fired` reports
+    /// whether it fired.
+    pub fn global_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.global_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Wait for `delay` before answering pings with a pong, to test client
+    /// ping timeouts. The connection does not read further messages while
+    /// waiting, other connections are not affected.
+    /// Pings and when their pong was sent are recorded by
+    /// `ServerHandle::received_pings`.
+    pub fn pong_delay(mut self, delay: Duration) -> Self {
+        self.pong_delay = Some(delay);
+        self
+    }
+
+    /// Answer the first `n` pings of each connection, then stop answering
+    /// them, like a server becoming unresponsive, to test client keepalive
+    /// failure thresholds. Pings and pongs are still recorded by
+    /// `ServerHandle::received_pings` and counted by
+    /// `ServerHandle::sent_pongs`.
+    pub fn pong_count(mut self, n: usize) -> Self {
+        self.pong_count = Some(n);
+        self
+    }
+
+    /// Answer requests out of order, to test how clients correlate responses
+    /// to their requests.
+    /// The id of each text message is read at the JSON pointer given by
+    /// `id_pointer` (`/id` by default), and its queued response is tagged
+    /// with the same id at the same pointer. Responses are buffered until a
+    /// batch of requests is received (see `out_of_order_batch`), then sent
+    /// in a shuffled order. Requests without id are answered right away.
+    pub fn out_of_order(mut self, enabled: bool) -> Self {
+        self.out_of_order = enabled;
+        self
+    }
+
+    /// Buffer `size` requests before sending their shuffled responses with
+    /// `out_of_order`, shuffling them with an RNG seeded with `seed` so the
+    /// order is the same on every run. Defaults to batches of 2 and seed 0.
+    /// The last batch is sent partial with the last queued response, or when
+    /// the server closes the connection.
+    pub fn out_of_order_batch(mut self, size: usize, seed: u64) -> Self {
+        self.out_of_order_batch = Some((size, seed));
+        self
+    }
+
+    /// Buffer the queued responses of each connection by `window`, and send
+    /// each full buffer in a shuffled order, to test clients of protocols
+    /// with sequence numbers which must re-sort the messages they receive.
+    /// Unlike `out_of_order`, which tags responses with the id of their
+    /// request, responses are shuffled by position only. The shuffles use an
+    /// RNG seeded with `seed`, so the order is the same on every run.
+    /// The buffer is flushed, shuffled, before the server closes the
+    /// connection, so no response is lost; once the client sent a close
+    /// frame nothing can be sent anymore, and buffered responses are dropped.
+    /// `start` returns a `ConfigError` if `window` is 0.
+    pub fn reorder(mut self, window: usize, seed: u64) -> Self {
+        self.reorder = Some((window, seed));
+        self
+    }
+
+    /// Seed the randomized features with a distinct RNG per connection, so
+    /// tests mixing them over many connections are reproducible while each
+    /// connection behaves differently. The RNG of a connection is seeded
+    /// with `seed ^ index`: the connection index for `out_of_order` and
+    /// `reorder` shuffles and `response_drop_rate` draws, replacing their
+    /// seed, and the index
+    /// of the accepted connection (starting at 0) for `flaky` drops,
+    /// replacing the seed of `flaky`. To reproduce a failure seen on a
+    /// connection, reuse the same seed, or compute its RNG seed from it.
+    pub fn chaos_seed(mut self, seed: u64) -> Self {
+        self.chaos_seed = Some(seed);
+        self
+    }
+
+    /// JSON pointer (RFC 6901) of the request id used by `out_of_order`.
+    pub fn id_pointer(mut self, pointer: String) -> Self {
+        self.id_pointer = Some(pointer);
+        self
+    }
+
+    /// Stop the server once `lifetime` elapsed since it started, closing
+    /// every connection, as `ServerHandle::stop` does.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Record a transcript of the text and binary messages received and
+    /// sent on every connection, in the order they happened, to debug a
+    /// conversation with `ServerHandle::transcript` or its tail with
+    /// `ServerHandle::recent_transcript`.
+    /// The transcript is a ring buffer of the `capacity` most recent
+    /// messages: once full, each new message evicts the oldest one, so its
+    /// memory stays bounded in long-running tests. Each entry holds a copy
+    /// of its message, so a large capacity with large messages uses as much
+    /// memory.
+    pub fn record_transcript(mut self, capacity: usize) -> Self {
+        self.transcript_capacity = Some(capacity);
+        self
+    }
+
+    /// Keep only the `limit` most recent received messages, to cap the
+    /// memory of long-running or high-throughput tests. The recorded
+    /// messages are a ring buffer: once full, each new message evicts the
+    /// oldest one, so `ServerHandle::received_messages` and `snapshot`
+    /// only see the retained messages, and
+    /// `ServerHandle::evicted_messages` counts the evicted ones to tell
+    /// whether they were truncated. By default every message is kept.
+    pub fn record_limit(mut self, limit: usize) -> Self {
+        self.record_limit = Some(limit);
+        self
+    }
+
+    /// Capture the value at the JSON pointer `pointer` (RFC 6901) of the
+    /// JSON text messages sent or received as `name`, e.g. a token issued
+    /// in a login response, to check later requests carry it back with
+    /// `ServerHandle::assert_contains`. Captures are per connection: each
+    /// one holds the latest value found on its connection, and is kept once
+    /// the connection is closed. Captured values are exposed by
+    /// `ServerHandle::captures`.
+    pub fn capture(mut self, pointer: &str, name: &str) -> Self {
+        self.captures.push((pointer.into(), name.into()));
+        self
+    }
+
+    /// Compare the messages received by the server with the golden file at
+    /// `path` once it stops, to catch unintended changes in what a client
+    /// sends. The file has one line per text or binary message, in the
+    /// order they were received: `<connection> text <JSON string>` or
+    /// `<connection> binary <hex bytes>`, e.g. `0 text "{\"id\":1}"`.
+    ///
+    /// The file is written on the first run, when it does not exist yet,
+    /// and should be committed. On the next runs, a mismatch makes the
+    /// server task panic with a line diff, which fails the test awaiting
+    /// `ServerHandle::join`. To accept an intended change, run the tests
+    /// with `SURIMI_UPDATE_SNAPSHOTS=1` to overwrite the golden files, then
+    /// review their diff.
+    pub fn snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snapshot = Some(path.into());
+        self
+    }
+
+    /// Call `callback` once when the server stops accepting connections,
+    /// with the cause of the shutdown, to clean up or assert the server
+    /// stopped and why.
+    pub fn on_shutdown<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(ShutdownCause) + Send + Sync + 'static,
+    {
+        self.on_shutdown = Some(Arc::new(callback));
+        self
+    }
+
+    /// Ignore text messages shorter than `bytes`, to mock a server which
+    /// only processes complete requests. Undersized messages are not
+    /// answered and are recorded by `ServerHandle::undersized_messages`.
+    /// They are treated independently unless `accumulate_undersized` is
+    /// enabled.
+    pub fn min_request_bytes(mut self, bytes: usize) -> Self {
+        self.min_request_bytes = Some(bytes);
+        self
+    }
+
+    /// Buffer the undersized messages of `min_request_bytes` until the
+    /// connection received enough bytes, then process the concatenated
+    /// messages as a single request.
+    pub fn accumulate_undersized(mut self, enabled: bool) -> Self {
+        self.accumulate_undersized = enabled;
+        self
+    }
+
+    /// Reject WebSocket handshakes whose `Sec-WebSocket-Version` is not
+    /// `version` with a 426 Upgrade Required response advertising it, to
+    /// test how clients fall back. By default, versions are checked by
+    /// tungstenite, which answers a 400 to any version but 13.
+    /// As tungstenite only completes version 13 handshakes, `version` must
+    /// be 13, or `start` returns a `ConfigError`.
+    /// The version offered by each client is recorded by
+    /// `ServerHandle::offered_versions`.
+    pub fn require_version(mut self, version: u8) -> Self {
+        self.require_version = Some(version);
+        self
+    }
+
+    /// Wait for `delay` before answering the close frame of a client
+    /// closing its connection, to test how clients handle a server slow to
+    /// complete the closing handshake.
+    pub fn close_delay(mut self, delay: Duration) -> Self {
+        self.close_delay = Some(delay);
+        self
+    }
+
+    /// Answer the close frame of a client closing its connection with a
+    /// close frame, completing the closing handshake, which is the default
+    /// required by RFC 6455. The reply echoes the client frame as
+    /// tungstenite does: a close frame without a code is answered with an
+    /// empty close frame, a code which may be sent on the wire with a 1000
+    /// normal closure, and a code reserved to report local failures (e.g.
+    /// 1005 or 1006) with a 1002 protocol error. Disabled, the connection
+    /// is dropped once the close frame is received, without reply, to test
+    /// how clients handle an incomplete closing handshake.
+    pub fn close_echo(mut self, enabled: bool) -> Self {
+        self.withhold_close_reply = !enabled;
+        self
+    }
+
+    /// Answer the WebSocket handshake of the first connection with a
+    /// redirect to `location` instead of upgrading it, like a load balancer
+    /// sending clients to another node. `status` must be one of 301, 302,
+    /// 307 or 308, or `start` returns a `ConfigError`.
+    /// Issued redirects are counted by `ServerHandle::redirects`.
+    pub fn redirect(mut self, status: u16, location: String) -> Self {
+        self.redirect = Some((status, location));
+        self
+    }
+
+    /// Redirect the first `count` connections with `redirect` instead of
+    /// only the first one.
+    pub fn redirect_count(mut self, count: usize) -> Self {
+        self.redirect_count = Some(count);
+        self
+    }
+
+    /// Answer the handshakes of the first connections with the given HTTP
+    /// statuses in order instead of 101 Switching Protocols, e.g.
+    /// `vec![503, 429]` to reject the first connection attempt with a 503,
+    /// the second one with a 429 and accept the next ones, like a server
+    /// recovering during a reconnect storm. A status of 101 accepts its
+    /// connection. The status answered to each handshake is recorded by
+    /// `ServerHandle::handshake_statuses`. `start` returns a `ConfigError`
+    /// if a status is not a valid HTTP status code.
+    pub fn handshake_statuses(mut self, statuses: Vec<u16>) -> Self {
+        self.handshake_statuses = statuses;
+        self
+    }
+
+    /// Require the handshake requests to carry the `user` and `pass`
+    /// credentials in an `Authorization: Basic` header, as clients connecting
+    /// to `ws://user:pass@host/` URLs do. Handshakes with other credentials,
+    /// a malformed header or none are rejected with a 401 and a
+    /// `WWW-Authenticate` header. The credentials presented to each
+    /// handshake are recorded by `ServerHandle::basic_credentials`, and a
+    /// status scripted by `handshake_statuses` takes precedence.
+    pub fn basic_auth(mut self, user: String, pass: String) -> Self {
+        self.basic_auth = Some((user, pass));
+        self
+    }
+
+    /// Reject handshakes whose request headers exceed `max` bytes with a
+    /// 431 Request Header Fields Too Large, then close the connection, like
+    /// servers with strict header limits, often behind proxies. The size
+    /// counts the header names and values, plus the `: ` separator and the
+    /// CRLF ending each line, but not the request line. A status scripted
+    /// by `handshake_statuses` takes precedence, except for requests with
+    /// more than 124 headers or a head over 64 KiB, which cannot be parsed
+    /// and are always answered with a 431.
+    /// Rejections are counted by `ServerHandle::oversized_headers`, and the
+    /// 431 is recorded by `ServerHandle::handshake_statuses`. By default
+    /// headers are not limited.
+    pub fn max_header_size(mut self, max: usize) -> Self {
+        self.max_header_size = Some(max);
+        self
+    }
+
+    /// Answer messages according to a `Scenario` state machine, tracked per
+    /// connection. Scenario rules take precedence over the other ways to
+    /// answer a message; messages matching no rule of the current state are
+    /// answered as if there was no scenario.
+    /// The current state of each connection is exposed by
+    /// `ServerHandle::scenario_state`.
+    pub fn scenario(mut self, scenario: Scenario) -> Self {
+        self.scenario = Some(scenario);
+        self
+    }
+
+    /// Bind the listener and start accepting connections in the background.
+    /// It is a shortcut for `bind` followed by `BoundServer::serve`.
+    pub async fn start(self) -> Result<ServerHandle, Box<dyn Error>> {
+        Ok(self.bind().await?.serve())
+    }
+
+    /// Bind the listener without accepting connections yet.
+    /// This is the first phase of the server lifecycle: the address is known
+    /// as soon as `bind` returns, through `BoundServer::local_addr`, and
+    /// connections are accepted once `BoundServer::serve` is called.
+    /// Connection attempts made in between wait in the listener backlog.
+    ///
+    /// # Examples
+    /// ```
+    /// use surimi::MockServer;
+    ///
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let bound = MockServer::default().bind().await?;
+    ///     let endpoint = format!("ws://{}", bound.local_addr());
+    ///     // e.g. configure a client factory with the endpoint here
+    ///
+    ///     let server = bound.serve();
+    ///     let (mut stream, _) = async_tungstenite::async_std::connect_async(endpoint).await?;
+    ///     assert_eq!(server.port(), stream.get_ref().peer_addr()?.port());
+    ///
+    ///     stream.close(None).await?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub async fn bind(mut self) -> Result<BoundServer, Box<dyn Error>> {
+        self.validate()?;
+        self.serialize_responses();
+        let response_headers = handshake::response_headers(&self.response_headers)?;
+        let listener = socket::bind(&self.options).await?;
+        let local_addr = listener.local_addr()?;
+
+        Ok(BoundServer {
+            server: self,
+            listener,
+            local_addr,
+            response_headers,
+        })
+    }
+
+    /// Bind the listener, then serve a single connection inline and return
+    /// the messages received on it, see `BoundServer::serve_once`.
+    /// It only suits servers listening on a known port, given with `port`,
+    /// or clients run concurrently which can find it out.
+    pub async fn serve_once(self) -> Result<Vec<RecordedMessage>, Box<dyn Error>> {
+        self.bind().await?.serve_once().await
+    }
+
+    /// Feed `bytes` to the server as the data received on one connection,
+    /// and return the bytes it wrote back, without a real socket.
+    /// The bytes are handled from the HTTP request head to the WebSocket
+    /// frames, as if the client sent them then closed its side.
+    /// It is an entry point for fuzzing the server and the clients it is
+    /// tested against: whatever the bytes, it must not panic.
+    pub async fn feed_raw(&self, bytes: &[u8]) -> Result<Vec<u8>, ConfigError> {
+        self.validate()?;
+        let response_headers = handshake::response_headers(&self.response_headers)?;
+        let state = State {
+            pending_sends: self.pending_sends,
+            ..State::default()
+        };
+
+        let transport = Memory::new(bytes);
+        let output = transport.output();
+        if let Err(error) = self
+            .handle_stream(transport, &state, &response_headers, false)
+            .await
+        {
+            log::debug!("fed connection failed: {}", error);
+        }
+        let output = output.lock().unwrap().clone();
+        Ok(output)
+    }
+
+    /// Start a server answering with `responses` and connect a client to it.
+    /// It is a shortcut for the most common test setup; use `start` and
+    /// connect manually to configure the server or the client further.
+    ///
+    /// # Examples
+    /// ```
+    /// use surimi::MockServer;
+    /// use futures_util::sink::SinkExt;
+    /// use futures_util::stream::StreamExt;
+    /// use async_tungstenite::tungstenite::protocol::Message;
+    /// use serde_json::json;
+    ///
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    ///     let (server, mut stream) =
+    ///         MockServer::spawn_with_client(vec![json!({"hello": "world"})]).await?;
+    ///
+    ///     stream.send(Message::Text("hello".into())).await?;
+    ///     let response = stream.next().await.unwrap()?;
+    ///     assert_eq!(response, Message::Text(json!({"hello": "world"}).to_string()));
+    ///     assert_eq!(server.received_messages().len(), 1);
+    ///
+    ///     stream.close(None).await?;
+    /// #   Ok(())
+    /// # }
+    /// ```
+    pub async fn spawn_with_client(
+        responses: Vec<Value>,
+    ) -> Result<(ServerHandle, WebSocketStream<ConnectStream>), Box<dyn Error>> {
+        let server = Self::default().responses(responses).start().await?;
+        let (stream, _) = async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        Ok((server, stream))
+    }
+
+    /// Serialize the JSON responses known before serving once, instead of
+    /// each time they are sent, which matters for large responses sent
+    /// many times, e.g. by `always_respond`.
+    /// Responses stay JSON values when they are changed as they are sent,
+    /// by `interpolate_env`, `transform_response`, `split_json` or
+    /// `out_of_order`.
+    fn serialize_responses(&mut self) {
+        if self.interpolate_env
+            || self.transform_response.is_some()
+            || self.split_json.is_some()
+            || self.out_of_order
+        {
+            return;
+        }
+        let queues = self
+            .responses_by
+            .iter_mut()
+            .flat_map(|(_, map)| map.values_mut())
+            .chain(std::iter::once(&mut self.responses));
+        for response in queues.flatten().chain(self.always_response.iter_mut()) {
+            response.serialize();
+        }
+    }
+
+    /// Check the options which cannot be validated by the builder methods.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(error) = &self.invalid_response {
+            return Err(error.clone());
+        }
+        if let Some(flaky) = self.flaky {
+            if !(0.0..=1.0).contains(&flaky.drop_probability) {
+                return Err(ConfigError::InvalidProbability(flaky.drop_probability));
+            }
+        }
+        if let Some((probability, _)) = self.response_drop_rate {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(ConfigError::InvalidProbability(probability));
+            }
+        }
+        if matches!(self.require_version, Some(version) if version != 13) {
+            return Err(ConfigError::InvalidValue {
+                option: "require_version",
+                reason: "only version 13 handshakes can be completed".into(),
+            });
+        }
+        if self.respond_every == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                option: "respond_every",
+                reason: "must answer at least every message (n > 0)".into(),
+            });
+        }
+        if matches!(&self.alternate, Some(responses) if responses.is_empty()) {
+            return Err(ConfigError::InvalidValue {
+                option: "alternate",
+                reason: "must alternate between at least one response".into(),
+            });
+        }
+        if let Some(OpcodeBehavior::Respond(_)) = self.opcode_handlers.get(&Opcode::Close) {
+            return Err(ConfigError::InvalidValue {
+                option: "opcode_handlers",
+                reason: "cannot respond to close frames".into(),
+            });
+        }
+        if self.options.concurrency_limit == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                option: "concurrency_limit",
+                reason: "must handle at least one connection (n > 0)".into(),
+            });
+        }
+        if matches!(self.out_of_order_batch, Some((0, _))) {
+            return Err(ConfigError::InvalidValue {
+                option: "out_of_order_batch",
+                reason: "must buffer at least one request (size > 0)".into(),
+            });
+        }
+        if matches!(self.reorder, Some((0, _))) {
+            return Err(ConfigError::InvalidValue {
+                option: "reorder",
+                reason: "must buffer at least one response (window > 0)".into(),
+            });
+        }
+        if self.bandwidth_limit == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                option: "bandwidth_limit",
+                reason: "must allow some bytes per second (bytes_per_sec > 0)".into(),
+            });
+        }
+        if let Some(bits) = self.rsv_bits.filter(|&bits| bits > 0b111) {
+            return Err(ConfigError::InvalidValue {
+                option: "set_rsv",
+                reason: format!("{:#b} is not a combination of the 3 reserved bits", bits),
+            });
+        }
+        if self.split_json == Some(0) {
+            return Err(ConfigError::InvalidValue {
+                option: "split_json",
+                reason: "must send at least one chunk (chunks > 0)".into(),
+            });
+        }
+        if let Some(&status) = self
+            .handshake_statuses
+            .iter()
+            .find(|&&status| StatusCode::from_u16(status).is_err())
+        {
+            return Err(ConfigError::InvalidValue {
+                option: "handshake_statuses",
+                reason: format!("{} is not a valid HTTP status code", status),
+            });
+        }
+        if let Some((status, _)) = self.redirect {
+            if ![301, 302, 307, 308].contains(&status) {
+                return Err(ConfigError::InvalidValue {
+                    option: "redirect",
+                    reason: format!("{} is not a supported redirect status", status),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn ws_handler(
+        self: Arc<Self>,
+        listener: &TcpListener,
+        state: Arc<State>,
+        response_headers: Arc<HeaderMap>,
+        stop: Receiver<()>,
+    ) {
+        let accept = self
+            .clone()
+            .accept_connections(listener, state.clone(), response_headers);
+        let mut shutdowns: Vec<Shutdown> = vec![
+            Box::pin(async {
+                accept.await;
+                ShutdownCause::Error
+            }),
+            Box::pin(async move {
+                // the sender is never used, it is dropped to stop the server
+                let _ = stop.recv().await;
+                ShutdownCause::Explicit
+            }),
+        ];
+        if let Some(timeout) = self.global_idle_timeout {
+            let state = &state;
+            shutdowns.push(Box::pin(async move {
+                idle_watchdog(state, timeout).await;
+                ShutdownCause::Deadman
+            }));
+        }
+        if let Some(lifetime) = self.lifetime {
+            shutdowns.push(Box::pin(async move {
+                task::sleep(lifetime).await;
+                ShutdownCause::Lifetime
+            }));
+        }
+
+        let (cause, _, _) = future::select_all(shutdowns).await;
+        state.close_all();
+        if let Some(callback) = &self.on_shutdown {
+            callback(cause);
+        }
+        if let Some(path) = &self.snapshot {
+            let received: Vec<_> = state
+                .received_messages
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect();
+            if let Err(mismatch) = snapshot::check(path, &received) {
+                panic!("{}", mismatch);
+            }
+        }
+    }
+
+    async fn accept_connections(
+        self: Arc<Self>,
+        listener: &TcpListener,
+        state: Arc<State>,
+        response_headers: Arc<HeaderMap>,
+    ) {
+        let mut flaky_rng = self
+            .flaky
+            .map(|flaky| (flaky.drop_probability, StdRng::seed_from_u64(flaky.seed)));
+        let semaphore = self
+            .options
+            .concurrency_limit
+            .map(|n| Arc::new(Semaphore::new(n)));
+        if let Some(delay) = self.options.accept_delay {
+            task::sleep(delay).await;
+        }
+
+        let accept_toggled = state
+            .accept_toggle
+            .as_ref()
+            .map(|(_, toggled)| toggled.clone());
+        let mut accepted = 0;
+        let mut incoming = listener.incoming();
+        loop {
+            let toggled = match &accept_toggled {
+                Some(toggled) => Either::Left(toggled.recv()),
+                None => Either::Right(future::pending()),
+            };
+            if state.accept_paused.load(Ordering::SeqCst) {
+                toggled.await.ok();
+                continue;
+            }
+            // a pause while waiting for a connection applies right away
+            let stream = match future::select(incoming.next(), toggled).await {
+                Either::Left((Some(stream), _)) => stream,
+                Either::Left((None, _)) => break,
+                Either::Right(_) => continue,
+            };
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::warn!("failed to accept connection: {}", error);
+                    continue;
+                }
+            };
+            self.tune_socket(&stream, &state);
+            // drawn in accept order so a given seed drops the same connections
+            let drop =
+                flaky_rng
+                    .as_mut()
+                    .is_some_and(|(drop_probability, rng)| match self.chaos_seed {
+                        Some(seed) => chaos_rng(seed, accepted).gen_bool(*drop_probability),
+                        None => rng.gen_bool(*drop_probability),
+                    });
+            accepted += 1;
+
+            let server = self.clone();
+            let state = state.clone();
+            let response_headers = response_headers.clone();
+            let semaphore = semaphore.clone();
+            #[cfg(feature = "tracing")]
+            let span = tracing::info_span!(
+                "connection",
+                peer = %stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default(),
+                index = tracing::field::Empty,
+            );
+            let handler = async move {
+                let _permit = match &semaphore {
+                    Some(semaphore) => Some(semaphore.acquire_arc().await),
+                    None => None,
+                };
+                state.in_flight_connections.fetch_add(1, Ordering::SeqCst);
+                // a failing connection must not stop the server for other clients
+                if let Err(error) = server
+                    .handle_stream(stream, &state, &response_headers, drop)
+                    .await
+                {
+                    log::warn!("connection failed: {}", error);
+                }
+                state.in_flight_connections.fetch_sub(1, Ordering::SeqCst);
+            };
+            #[cfg(feature = "tracing")]
+            let handler = tracing::Instrument::instrument(handler, span);
+            self.spawn(handler);
+        }
+    }
+
+    /// Apply the socket options to an accepted stream, only logging
+    /// failures so the connection is still served.
+    fn tune_socket(&self, stream: &TcpStream, state: &State) {
+        if let Some(time) = self.options.tcp_keepalive {
+            if let Err(error) = socket::set_keepalive(stream, time) {
+                log::warn!("failed to enable TCP keepalive: {}", error);
+            }
+        }
+        let (recv, send) = (self.options.recv_buffer_size, self.options.send_buffer_size);
+        if recv.is_some() || send.is_some() {
+            match socket::set_buffer_sizes(stream, recv, send) {
+                Ok(sizes) => state.buffer_sizes.lock().unwrap().push(sizes),
+                Err(error) => log::warn!("failed to set socket buffer sizes: {}", error),
+            }
+        }
+    }
+
+    // the handshake callback signature is imposed by tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn handle_stream<S: Transport>(
+        &self,
+        mut stream: S,
+        state: &State,
+        response_headers: &HeaderMap,
+        drop: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let accepted_at = Instant::now();
+        let (head, buffer) = match RequestHead::read(&mut stream).await {
+            // too many or too large headers to even parse the request
+            Err(error) if self.max_header_size.is_some() && HeadTooLarge::caused(&error) => {
+                state.oversized_headers.fetch_add(1, Ordering::SeqCst);
+                state.handshake_statuses.lock().unwrap().push(431);
+                let reason = "Request Header Fields Too Large";
+                http::write_response(&mut stream, 431, "text/plain", reason).await?;
+                return Ok(());
+            }
+            read => read?,
+        };
+        let draining = state.draining.load(Ordering::SeqCst);
+        if self.health_check && !head.upgrade && head.method == "GET" && head.path == "/healthz" {
+            let (status, body) = if draining {
+                (503, "Draining")
+            } else {
+                (200, "OK")
+            };
+            http::write_response(&mut stream, status, "text/plain", body).await?;
+            return Ok(());
+        }
+        if !head.upgrade {
+            http::respond(
+                &mut stream,
+                &self.http_routes,
+                self.http_fallback_chunks.as_deref(),
+                &head.method,
+                &head.path,
+            )
+            .await?;
+            return Ok(());
+        }
+        state.offered_versions.lock().unwrap().push(head.version);
+        if draining {
+            state.handshake_statuses.lock().unwrap().push(503);
+            http::write_response(&mut stream, 503, "text/plain", "Draining").await?;
+            return Ok(());
+        }
+        if let Some(version) = self.require_version.filter(|&v| head.version != Some(v)) {
+            state.handshake_statuses.lock().unwrap().push(426);
+            http::write_response_with_headers(
+                &mut stream,
+                426,
+                &[("Sec-WebSocket-Version", &version.to_string())],
+                "text/plain",
+                "Upgrade Required",
+            )
+            .await?;
+            return Ok(());
+        }
+        if let Some((status, location)) = &self.redirect {
+            let count = self.redirect_count.unwrap_or(1);
+            let redirected = state
+                .redirects
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |redirects| {
+                    (redirects < count).then(|| redirects + 1)
+                })
+                .is_ok();
+            if redirected {
+                state.handshake_statuses.lock().unwrap().push(*status);
+                http::write_response_with_headers(
+                    &mut stream,
+                    *status,
+                    &[("Location", location)],
+                    "text/plain",
+                    "",
+                )
+                .await?;
+                return Ok(());
+            }
+        }
+        if drop {
+            state.dropped_connections.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+        if self.hang_handshake {
+            state.hung_handshakes.fetch_add(1, Ordering::SeqCst);
+            // discard whatever the client sends until it closes
+            let mut buffer = [0; 1024];
+            while async_std::io::ReadExt::read(&mut stream, &mut buffer).await? > 0 {}
+            return Ok(());
+        }
+        if let Some(preamble) = &self.preamble {
+            stream.write_all(preamble).await?;
+            stream.flush().await?;
+            state.sent_preambles.fetch_add(1, Ordering::SeqCst);
+        }
+        if let Some(bytes) = self.truncate_handshake {
+            let response = handshake::switching_protocols(
+                head.key.as_deref().unwrap_or_default(),
+                response_headers,
+            );
+            stream
+                .write_all(&response[..bytes.min(response.len())])
+                .await?;
+            stream.flush().await?;
+            state.truncated_handshakes.fetch_add(1, Ordering::SeqCst);
+            return Ok(());
+        }
+
+        let peer = stream.peer_addr();
+        let mut head_bytes = buffer;
+        let frames = head_bytes.split_off(head.len);
+        let stream = Replay::new(vec![head_bytes, frames], stream);
+        let mut query = QueryParams::new();
+        let mut responses = None;
+        let mut rejected = false;
+        let handshake = async_tungstenite::accept_hdr_async(
+            stream,
+            |request: &Request,
+             mut response: HandshakeResponse|
+             -> Result<HandshakeResponse, ErrorResponse> {
+                let attempt = state.handshakes.fetch_add(1, Ordering::SeqCst);
+                let status = self
+                    .handshake_statuses
+                    .get(attempt)
+                    .and_then(|&status| StatusCode::from_u16(status).ok())
+                    .unwrap_or(StatusCode::SWITCHING_PROTOCOLS);
+                let oversized = self
+                    .max_header_size
+                    .is_some_and(|max| handshake::header_size(request.headers()) > max);
+                let status = if status == StatusCode::SWITCHING_PROTOCOLS && oversized {
+                    state.oversized_headers.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                } else {
+                    status
+                };
+                let unauthorized = status == StatusCode::SWITCHING_PROTOCOLS
+                    && self.basic_auth.as_ref().is_some_and(|expected| {
+                        let credentials = handshake::basic_credentials(request.headers());
+                        let authorized = credentials.as_ref() == Some(expected);
+                        state.basic_credentials.lock().unwrap().push(credentials);
+                        !authorized
+                    });
+                let status = if unauthorized {
+                    StatusCode::UNAUTHORIZED
+                } else {
+                    status
+                };
+                state
+                    .handshake_statuses
+                    .lock()
+                    .unwrap()
+                    .push(status.as_u16());
+                if status != StatusCode::SWITCHING_PROTOCOLS {
+                    rejected = true;
+                    let mut error = ErrorResponse::new(status.canonical_reason().map(String::from));
+                    *error.status_mut() = status;
+                    if unauthorized {
+                        error.headers_mut().insert(
+                            WWW_AUTHENTICATE,
+                            HeaderValue::from_static("Basic realm=\"surimi\""),
+                        );
+                    }
+                    return Err(error);
+                }
+                query = handshake::parse_query(request.uri());
+                if let Some((key, map)) = &self.responses_by {
+                    responses = map.get(&key(request)).cloned().map(VecDeque::from);
+                }
+                response.headers_mut().extend(response_headers.clone());
+                if let Some(key) = head.key.as_deref().filter(|_| self.corrupt_accept_key) {
+                    let accept = handshake::corrupt_accept_key(key);
+                    if let Ok(accept) = HeaderValue::from_str(&accept) {
+                        response.headers_mut().insert(SEC_WEBSOCKET_ACCEPT, accept);
+                        state.corrupted_accept_keys.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                Ok(response)
+            },
+        )
+        .await;
+        let mut socket = match handshake {
+            Err(WsError::Http(_)) if rejected => return Ok(()),
+            handshake => handshake?,
+        };
+        let pings_handled = matches!(
+            self.opcode_handlers.get(&Opcode::Ping),
+            Some(OpcodeBehavior::Respond(_) | OpcodeBehavior::Ignore)
+        );
+        if self.pong_count.is_some() || pings_handled {
+            socket.get_mut().filter_pongs();
+        }
+        if self.unsupported != UnsupportedBehavior::Fail {
+            socket.get_mut().filter_reserved();
+        }
+        let connection = state.served_connections.fetch_add(1, Ordering::SeqCst);
+        let traffic = socket.get_ref().traffic();
+        let handshake_duration = accepted_at.elapsed();
+        let _stats =
+            StatsRecorder::new(state, connection, peer, traffic.clone(), handshake_duration);
+        let mut socket = Tracked::new(socket, traffic, state, connection);
+        #[cfg(feature = "tracing")]
+        {
+            tracing::Span::current().record("index", connection);
+            tracing::info!(path = %head.path, "handshake completed");
+        }
+
+        let query_response = self
+            .query_rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| {
+                query
+                    .get(&rule.key)
+                    .is_some_and(|values| values.contains(&rule.value))
+            })
+            .map(|(index, rule)| (index, rule.response.clone()));
+        let mut responses: VecDeque<_> = responses.unwrap_or_else(|| self.responses.clone().into());
+        let session = query
+            .get("session")
+            .and_then(|ids| ids.first())
+            .filter(|_| self.resumable);
+        if let Some(id) = session {
+            let consumed = state.resume_session(connection, id);
+            responses.drain(..consumed.min(responses.len()));
+        }
+        *state.query_params.lock().unwrap() = query;
+
+        let outbound = state.connect(connection);
+        let result = self
+            .serve_connection(
+                &mut socket,
+                connection,
+                state,
+                outbound,
+                responses,
+                query_response,
+            )
+            .await;
+        let reason = match &result {
+            Ok(reason) => reason.clone(),
+            Err(error) => DisconnectReason::Error(error.to_string()),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::info!(reason = ?reason, "connection closed");
+        state.disconnect(connection, reason);
+        result.map(|_| ())
+    }
+
+    async fn serve_connection<C: Connection>(
+        &self,
+        socket: &mut C,
+        connection: usize,
+        state: &State,
+        mut outbound: Receiver<Pushed>,
+        mut responses: VecDeque<MockResponse>,
+        query_response: Option<(usize, Value)>,
+    ) -> Result<DisconnectReason, Box<dyn Error>> {
+        let mut sent_responses = 0;
+        let (batch_size, seed) = self.out_of_order_batch.unwrap_or((2, 0));
+        let id_pointer = self.id_pointer.as_deref().unwrap_or("/id");
+        let mut out_of_order = (Vec::new(), self.rng(seed, connection));
+        let mut reorder = self
+            .reorder
+            .map(|(_, seed)| (Vec::new(), self.rng(seed, connection)));
+        let mut response_drops = self
+            .response_drop_rate
+            .map(|(probability, seed)| (probability, self.rng(seed, connection)));
+        let mut text_messages = 0;
+        let mut alternated = 0;
+        let mut undersized = String::new();
+        let mut pings = 0;
+        let mut scenario_state = self.scenario.as_ref().map(|scenario| {
+            let initial = scenario.initial_state().to_string();
+            state.set_scenario_state(connection, &initial);
+            initial
+        });
+
+        if let Some((code, reason)) = &self.close_on_connect {
+            let frame = CloseFrame {
+                code: *code,
+                reason: reason.clone().into(),
+            };
+            state
+                .connect_close_frames
+                .lock()
+                .unwrap()
+                .insert(connection, frame.clone());
+            socket.send(Message::Close(Some(frame))).await?;
+            // messages sent before the client's close reply are discarded
+            while let Some(Ok(_)) = socket.next().await {}
+            return Ok(DisconnectReason::Closed);
+        }
+
+        let mut subscriptions = Subscriptions::new(&self.subscriptions);
+        let mut reserved = socket.take_reserved();
+
+        loop {
+            // incoming messages are polled first, then pushed ones, and
+            // subscriptions stream whenever nothing else is ready
+            let streaming = if subscriptions.is_streaming() {
+                Either::Left(future::ready(()))
+            } else {
+                Either::Right(future::pending())
+            };
+            let dropped = match &mut reserved {
+                Some(reserved) => Either::Left(reserved.next()),
+                None => Either::Right(future::pending()),
+            };
+            let received = future::select(socket.next(), outbound.next());
+            let event = match future::select(future::select(received, dropped), streaming).await {
+                Either::Left((Either::Left((Either::Left((message, _)), _)), _)) => {
+                    Event::Received(message)
+                }
+                Either::Left((Either::Left((Either::Right((response, _)), _)), _)) => {
+                    Event::Pushed(response)
+                }
+                Either::Left((Either::Right((opcode, _)), _)) => Event::Reserved(opcode),
+                Either::Right(_) => Event::Streaming,
+            };
+            // only messages sent while handling a received one answer it
+            socket.answering(matches!(event, Event::Received(_)));
+            let message = match event {
+                Event::Received(Some(Err(WsError::Protocol(
+                    error @ (ProtocolError::ControlFrameTooBig
+                    | ProtocolError::FragmentedControlFrame),
+                )))) if self.strict_control_frames => {
+                    state
+                        .control_frame_violations
+                        .fetch_add(1, Ordering::SeqCst);
+                    socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Protocol,
+                            reason: error.to_string().into(),
+                        })))
+                        .await?;
+                    return Err(error.into());
+                }
+                // reserved frames reach tungstenite if they are not filtered
+                Event::Received(Some(Err(
+                    error @ WsError::Protocol(ProtocolError::InvalidOpcode(opcode)),
+                ))) => {
+                    state
+                        .unsupported_frames
+                        .lock()
+                        .unwrap()
+                        .push(UnsupportedFrame { connection, opcode });
+                    return Err(error.into());
+                }
+                Event::Received(Some(message)) => {
+                    state.touch();
+                    let message = message?;
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(message = %message, "message received");
+                    if let Some(inspector) = &self.inspect_request {
+                        inspector(&message);
+                    }
+                    message
+                }
+                Event::Pushed(Some(Pushed::Response(response))) => {
+                    self.respond(socket, state, connection, &response).await?;
+                    continue;
+                }
+                Event::Pushed(Some(Pushed::Relayed(message))) => {
+                    socket.send(message).await?;
+                    continue;
+                }
+                Event::Reserved(Some(opcode)) => {
+                    state
+                        .unsupported_frames
+                        .lock()
+                        .unwrap()
+                        .push(UnsupportedFrame { connection, opcode });
+                    match &self.unsupported {
+                        UnsupportedBehavior::Fail | UnsupportedBehavior::Ignore => {}
+                        UnsupportedBehavior::Close(code) => {
+                            self.flush_buffered(
+                                socket,
+                                state,
+                                connection,
+                                &mut out_of_order,
+                                &mut reorder,
+                            )
+                            .await?;
+                            // the client's close reply ends the loop
+                            socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: *code,
+                                    reason: "".into(),
+                                })))
+                                .await?
+                        }
+                        UnsupportedBehavior::Respond(response) => {
+                            self.respond(socket, state, connection, &response.clone().into())
+                                .await?
+                        }
+                    }
+                    continue;
+                }
+                // the transport drops the sender of the reserved opcodes with the stream
+                Event::Reserved(None) => {
+                    reserved = None;
+                    continue;
+                }
+                Event::Streaming => {
+                    if let Some(response) = subscriptions.next() {
+                        let response = self.transform(response);
+                        socket.send(Message::Text(response.to_string())).await?;
+                    }
+                    continue;
+                }
+                Event::Received(None) => break,
+                // the outbound sender lives as long as the connection is registered
+                Event::Pushed(None) => {
+                    self.flush_buffered(socket, state, connection, &mut out_of_order, &mut reorder)
+                        .await?;
+                    socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Away,
+                            reason: "server going away".into(),
+                        })))
+                        .await?;
+                    break;
+                }
+            };
+
+            if message.is_text() || message.is_binary() {
+                state.record_message(RecordedMessage {
+                    connection,
+                    message: message.clone(),
+                });
+
+                if let Some((max, error)) = &self.large_messages {
+                    if message.len() > *max {
+                        state.oversized_messages.fetch_add(1, Ordering::SeqCst);
+                        if let Message::Text(text) = &message {
+                            state.log_match(connection, text, MatchedRule::Oversized);
+                        }
+                        let error = self.transform(error.clone());
+                        socket.send(Message::Text(error.to_string())).await?;
+                        continue;
+                    }
+                }
+
+                if self.relay {
+                    let except = (!self.relay_to_sender).then_some(connection);
+                    state.broadcast(Pushed::Relayed(message), except);
+                    continue;
+                }
+
+                if let Some((probability, rng)) = &mut response_drops {
+                    if rng.gen_bool(*probability) {
+                        state.dropped_responses.fetch_add(1, Ordering::SeqCst);
+                        if let Message::Text(text) = &message {
+                            state.log_match(connection, text, MatchedRule::Dropped);
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            let handler = self.opcode_handlers.get(&Opcode::of(&message));
+            match message {
+                Message::Text(mut text) => {
+                    let total_count = state.text_messages.fetch_add(1, Ordering::SeqCst) + 1;
+                    match handler {
+                        Some(OpcodeBehavior::Respond(response)) => {
+                            state.log_match(connection, &text, MatchedRule::Opcode);
+                            self.respond(socket, state, connection, response).await?;
+                            continue;
+                        }
+                        Some(OpcodeBehavior::Ignore) => {
+                            state.log_match(connection, &text, MatchedRule::Opcode);
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    if let Some(min) = self.min_request_bytes {
+                        if undersized.len() + text.len() < min {
+                            state.log_match(connection, &text, MatchedRule::Undersized);
+                            state
+                                .undersized_messages
+                                .lock()
+                                .unwrap()
+                                .push(RecordedMessage {
+                                    connection,
+                                    message: Message::Text(text.clone()),
+                                });
+                            if self.accumulate_undersized {
+                                undersized.push_str(&text);
+                            }
+                            continue;
+                        }
+                        text.insert_str(0, &std::mem::take(&mut undersized));
+                    }
+                    if subscriptions.control(&parse_message(&text)) {
+                        state.set_subscriptions(connection, subscriptions.active());
+                        state.log_match(connection, &text, MatchedRule::Subscription);
+                        continue;
+                    }
+                    text_messages += 1;
+                    if text_messages % self.respond_every.unwrap_or(1) != 0 {
+                        state.log_match(connection, &text, MatchedRule::Skipped);
+                        continue;
+                    }
+                    if let (Some(scenario), Some(current)) = (&self.scenario, &mut scenario_state) {
+                        if let Some(transition) =
+                            scenario.transition(current, &parse_message(&text))
+                        {
+                            state.log_match(
+                                connection,
+                                &text,
+                                MatchedRule::Scenario {
+                                    state: current.clone(),
+                                    rule: transition.rule,
+                                    name: transition.name.map(String::from),
+                                },
+                            );
+                            let response = transition.response.cloned().map(MockResponse::from);
+                            *current = transition.next_state.to_string();
+                            state.set_scenario_state(connection, current);
+                            if let Some(response) = response {
+                                self.respond(socket, state, connection, &response).await?;
+                            }
+                            continue;
+                        }
+                    }
+                    let request = parse_message(&text);
+                    if !self.matchers.is_empty() {
+                        let message = Message::Text(text.clone());
+                        let matched = self
+                            .matchers
+                            .iter()
+                            .enumerate()
+                            .find_map(|(index, matcher)| Some((index, matcher.matches(&message)?)));
+                        if let Some((index, response)) = matched {
+                            state.log_match(connection, &text, MatchedRule::Matcher(index));
+                            self.respond(socket, state, connection, &response).await?;
+                            continue;
+                        }
+                    }
+                    if let Some((pointer, responses)) = &self.seq_responses {
+                        let seq = request.pointer(pointer).and_then(Value::as_u64);
+                        if let Some((seq, response)) =
+                            seq.and_then(|seq| Some((seq, responses.get(&seq)?)))
+                        {
+                            state.log_match(connection, &text, MatchedRule::Seq(seq));
+                            let response =
+                                MockResponse::from(response.clone()).with_id(pointer, seq.into());
+                            self.respond(socket, state, connection, &response).await?;
+                            continue;
+                        }
+                    }
+                    if let Some((rule, response)) = &query_response {
+                        state.log_match(connection, &text, MatchedRule::Query(*rule));
+                        self.respond(socket, state, connection, &response.clone().into())
+                            .await?;
+                        continue;
+                    }
+                    if let Some(responder) = &self.async_responder {
+                        state.log_match(connection, &text, MatchedRule::Responder);
+                        let response = responder(&parse_message(&text)).await;
+                        self.respond(socket, state, connection, &response.into())
+                            .await?;
+                        continue;
+                    }
+                    if let Some(responder) = &self.total_count_responder {
+                        state.log_match(connection, &text, MatchedRule::TotalCount);
+                        self.respond(socket, state, connection, &responder(total_count))
+                            .await?;
+                        continue;
+                    }
+                    if let Some(alternate) = &self.alternate {
+                        let index = alternated % alternate.len();
+                        alternated += 1;
+                        state.log_match(connection, &text, MatchedRule::Alternate(index));
+                        self.respond(socket, state, connection, &alternate[index].clone().into())
+                            .await?;
+                        continue;
+                    }
+                    if let Some(response) = responses.pop_front() {
+                        state.log_match(connection, &text, MatchedRule::Queue);
+                        state.consume_session(connection);
+                        let id = parse_message(&text).pointer(id_pointer).cloned();
+                        let batch = match id {
+                            Some(id) if self.out_of_order => {
+                                let (buffered, rng) = &mut out_of_order;
+                                buffered.push(response.with_id(id_pointer, id));
+                                // a partial batch is sent with the last response
+                                if buffered.len() < batch_size && !responses.is_empty() {
+                                    continue;
+                                }
+                                buffered.shuffle(rng);
+                                std::mem::take(buffered)
+                            }
+                            _ => vec![response],
+                        };
+                        let batch = match (&mut reorder, self.reorder) {
+                            (Some((buffered, rng)), Some((window, _))) => {
+                                buffered.extend(batch);
+                                if buffered.len() < window {
+                                    continue;
+                                }
+                                buffered.shuffle(rng);
+                                std::mem::take(buffered)
+                            }
+                            _ => batch,
+                        };
+                        for response in batch {
+                            self.respond(socket, state, connection, &response).await?;
+                            sent_responses += 1;
+                            if self.reset_after == Some(sent_responses) {
+                                // the socket is dropped by the caller, resetting the connection
+                                socket.reset_on_close()?;
+                                return Ok(DisconnectReason::Reset);
+                            }
+                            if self.abnormal_close_after == Some(sent_responses) {
+                                // the socket is dropped by the caller, without close frame
+                                return Ok(DisconnectReason::Abnormal);
+                            }
+                        }
+                        continue;
+                    }
+                    if let Some(response) = &self.always_response {
+                        state.log_match(connection, &text, MatchedRule::Always);
+                        self.respond(socket, state, connection, response).await?;
+                        continue;
+                    }
+                    state.log_match(connection, &text, MatchedRule::Exhausted);
+                    match &self.exhausted {
+                        ExhaustBehavior::SendText(text) => {
+                            socket.send(Message::Text(text.clone())).await?
+                        }
+                        ExhaustBehavior::Close(code) => {
+                            self.flush_buffered(
+                                socket,
+                                state,
+                                connection,
+                                &mut out_of_order,
+                                &mut reorder,
+                            )
+                            .await?;
+                            // the client's close reply ends the loop
+                            socket
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: *code,
+                                    reason: "".into(),
+                                })))
+                                .await?
+                        }
+                        ExhaustBehavior::Silent => {}
+                        ExhaustBehavior::Echo => socket.send(Message::Text(text)).await?,
+                    }
+                }
+                Message::Binary(data) => match handler {
+                    Some(OpcodeBehavior::Respond(response)) => {
+                        self.respond(socket, state, connection, response).await?;
+                    }
+                    Some(OpcodeBehavior::Ignore) => {}
+                    _ => {
+                        let message = Message::Binary(data);
+                        let matched = self
+                            .matchers
+                            .iter()
+                            .find_map(|matcher| matcher.matches(&message));
+                        if let Some(response) = matched.as_ref().or(self.always_response.as_ref()) {
+                            self.respond(socket, state, connection, response).await?;
+                        }
+                    }
+                },
+                Message::Ping(payload) => {
+                    let received_at = Instant::now();
+                    let ponged = self.pong_count.is_none_or(|n| pings < n)
+                        && !matches!(
+                            handler,
+                            Some(OpcodeBehavior::Respond(_) | OpcodeBehavior::Ignore)
+                        );
+                    pings += 1;
+                    socket.drop_pongs(!ponged);
+                    if let Some(delay) = self.pong_delay.filter(|_| ponged) {
+                        task::sleep(delay).await;
+                    }
+                    // tungstenite queued the pong, flushing writes it out
+                    socket.flush().await?;
+                    if ponged {
+                        state.sent_pongs.fetch_add(1, Ordering::SeqCst);
+                    }
+                    state.received_pings.lock().unwrap().push(RecordedPing {
+                        connection,
+                        payload,
+                        received_at,
+                        ponged_at: ponged.then(Instant::now),
+                    });
+                    if let Some(OpcodeBehavior::Respond(response)) = handler {
+                        self.respond(socket, state, connection, response).await?;
+                    }
+                }
+                Message::Pong(payload) => {
+                    match handler {
+                        Some(OpcodeBehavior::Respond(response)) => {
+                            self.respond(socket, state, connection, response).await?;
+                            continue;
+                        }
+                        Some(OpcodeBehavior::Ignore) => continue,
+                        _ => {}
+                    }
+                    if let Some(callback) = &self.on_pong {
+                        callback(&payload);
+                    }
+                    state.received_pongs.lock().unwrap().push(RecordedPong {
+                        connection,
+                        payload,
+                        received_at: Instant::now(),
+                    });
+                }
+                Message::Close(frame) => {
+                    *state.client_close_frame.lock().unwrap() = frame.map(CloseFrame::into_owned);
+                    if self.withhold_close_reply {
+                        // the socket is dropped by the caller before the
+                        // close reply queued by tungstenite is written
+                        return Ok(DisconnectReason::Closed);
+                    }
+                    if let Some(OpcodeBehavior::Ignore) = handler {
+                        // the socket is dropped by the caller, without close reply
+                        return Ok(DisconnectReason::Abnormal);
+                    }
+                    if let Some(delay) = self.close_delay {
+                        task::sleep(delay).await;
+                    }
+                    // tungstenite queued the close reply, flushing writes it
+                    // out then reports the connection as closed
+                    match socket.flush().await {
+                        Ok(()) | Err(WsError::ConnectionClosed) => {}
+                        Err(error) => return Err(error.into()),
+                    }
+                    break;
+                }
+            }
+        }
+        Ok(DisconnectReason::Closed)
+    }
+
+    /// Send the responses still buffered by `out_of_order` and `reorder`,
+    /// shuffled, e.g. before closing the connection.
+    async fn flush_buffered<C: Connection>(
+        &self,
+        socket: &mut C,
+        state: &State,
+        connection: usize,
+        out_of_order: &mut (Vec<MockResponse>, StdRng),
+        reorder: &mut Option<(Vec<MockResponse>, StdRng)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let (batch, rng) = out_of_order;
+        batch.shuffle(rng);
+        let mut buffered = std::mem::take(batch);
+        if let Some((reordered, rng)) = reorder {
+            reordered.append(&mut buffered);
+            reordered.shuffle(rng);
+            buffered = std::mem::take(reordered);
+        }
+        for response in buffered {
+            self.respond(socket, state, connection, &response).await?;
+        }
+        Ok(())
+    }
+
+    /// `value` transformed by `transform_response`, if set.
+    fn transform(&self, value: Value) -> Value {
+        match &self.transform_response {
+            Some(transform) => transform(value),
+            None => value,
+        }
+    }
+
+    /// Send `response` stamped with the current time of the clock,
+    /// interpolated with the environment variables if enabled and
+    /// transformed by `transform_response`, once the latency of
+    /// `latency_ramp` elapsed.
+    async fn respond<C: Connection>(
+        &self,
+        socket: &mut C,
+        state: &State,
+        connection: usize,
+        response: &MockResponse,
+    ) -> Result<(), Box<dyn Error>> {
+        let now = self
+            .clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock());
+        let mut response = response.stamp(now);
+        if self.interpolate_env {
+            response = response.interpolate_env(self.missing_env)?;
+        }
+        if let Some(transform) = &self.transform_response {
+            response = response.map_json(&|value| transform(value));
+        }
+        if let Some((start, end, over)) = self.latency_ramp {
+            let elapsed = state.started_at.map_or(Duration::ZERO, |at| at.elapsed());
+            let delay = ramp(start, end, over, elapsed);
+            state.response_delays.lock().unwrap().push(delay);
+            task::sleep(delay).await;
+        }
+        match (self.split_json, &response) {
+            (Some(chunks), MockResponse::Json(value)) => {
+                for chunk in split_text(&value.to_string(), chunks) {
+                    let chunk = MockResponse::Text(chunk.into());
+                    self.write_response(socket, state, connection, &chunk)
+                        .await?;
+                }
+                Ok(())
+            }
+            _ => {
+                self.write_response(socket, state, connection, &response)
+                    .await
+            }
+        }
+    }
+
+    /// Write `response` paced by `write_stall` or `bandwidth_limit`, with
+    /// the reserved bits of `set_rsv`, masked if `mask_server_frames` is set.
+    async fn write_response<C: Connection>(
+        &self,
+        socket: &mut C,
+        state: &State,
+        connection: usize,
+        response: &MockResponse,
+    ) -> Result<(), Box<dyn Error>> {
+        if let MockResponse::Raw(bytes) = response {
+            state.record_transcript(connection, Direction::Sent, &response.to_message());
+            socket.write_raw(bytes).await?;
+            state.raw_responses.lock().unwrap().push(RecordedMessage {
+                connection,
+                message: response.to_message(),
+            });
+            return Ok(());
+        }
+        let pacing = match (self.write_stall, self.bandwidth_limit) {
+            (Some((after, stall)), _) => Pacing::Stalled { after, stall },
+            (None, Some(bytes_per_sec)) => Pacing::Throttled(bytes_per_sec),
+            // tungstenite cannot compress, or set the header bits of, a frame
+            (None, None)
+                if self.rsv_bits.is_some()
+                    || self.mask_server_frames
+                    || matches!(response, MockResponse::Compressed(_)) =>
+            {
+                Pacing::Immediate
+            }
+            (None, None) => return Ok(socket.send(response.to_message()).await?),
+        };
+        let mut frame = match response {
+            MockResponse::Compressed(_) => frame::compressed(response)?,
+            _ => frame::uncompressed(response),
+        };
+        if let Some(bits) = self.rsv_bits {
+            let header = frame.header_mut();
+            header.rsv1 |= bits & 0b100 != 0;
+            header.rsv2 |= bits & 0b010 != 0;
+            header.rsv3 |= bits & 0b001 != 0;
+        }
+        if self.mask_server_frames {
+            frame.header_mut().mask = Some(rand::random());
+            state.masked_frames.fetch_add(1, Ordering::SeqCst);
+        }
+        state.record_transcript(connection, Direction::Sent, &response.to_message());
+        socket.write_frame(frame, pacing).await?;
+        if self.rsv_bits.is_some() {
+            state.rsv_frames.lock().unwrap().push(RecordedMessage {
+                connection,
+                message: response.to_message(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// BoundServer is a MockServer whose listener is bound but which does not
+/// accept connections yet. It is returned by `MockServer::bind`.
+pub struct BoundServer {
+    server: MockServer,
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    response_headers: HeaderMap,
+}
+
+impl BoundServer {
+    /// Address the listener is bound to. If the server was configured with
+    /// port 0, it contains the port picked by the OS.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Accept a single connection and serve it inline, without spawning a
+    /// task, then return the text and binary messages received on it, in
+    /// the order they were received. Unlike `serve`, which accepts
+    /// connections in the background until the server is stopped, the
+    /// returned future blocks the calling one until this connection closes,
+    /// and nothing outlives it once it completes, which makes single
+    /// connection tests deterministic. The client must then be run
+    /// concurrently, e.g. with `futures::join!`.
+    /// A plain HTTP request, e.g. a health check, counts as the one
+    /// connection. An error is returned if accepting or serving the
+    /// connection fails.
+    pub async fn serve_once(self) -> Result<Vec<RecordedMessage>, Box<dyn Error>> {
+        let state = State {
+            pending_sends: self.server.pending_sends,
+            transcript_capacity: self.server.transcript_capacity,
+            record_limit: self.server.record_limit,
+            capture_pointers: self.server.captures.clone(),
+            started_at: Some(Instant::now()),
+            ..State::default()
+        };
+        let (stream, _) = self.listener.accept().await?;
+        self.server.tune_socket(&stream, &state);
+        // boxed as the serving future is too large for the caller's stack
+        Box::pin(
+            self.server
+                .handle_stream(stream, &state, &self.response_headers, false),
+        )
+        .await?;
+        let received = state.received_messages.into_inner().unwrap();
+        Ok(received.into())
+    }
+
+    /// Start accepting connections in the background.
+    pub fn serve(self) -> ServerHandle {
+        let host = self.server.options.host.clone();
+        let (stopper, stop) = async_std::channel::bounded(1);
+        let state = Arc::new(State {
+            pending_sends: self.server.pending_sends,
+            transcript_capacity: self.server.transcript_capacity,
+            record_limit: self.server.record_limit,
+            capture_pointers: self.server.captures.clone(),
+            stopper: Mutex::new(Some(stopper)),
+            accept_toggle: Some(async_std::channel::unbounded()),
+            listening: AtomicBool::new(true),
+            started_at: Some(Instant::now()),
+            ..State::default()
+        });
+
+        let handler_state = state.clone();
+        let listener = self.listener;
+        let response_headers = Arc::new(self.response_headers);
+        let server = Arc::new(self.server);
+        let (done, task) = async_std::channel::bounded(1);
+        server.clone().spawn(async move {
+            let state = handler_state.clone();
+            // the listener is dropped with the handler, freeing the port
+            let handler = async move {
+                server
+                    .ws_handler(&listener, handler_state, response_headers, stop)
+                    .await
+            };
+            let result = AssertUnwindSafe(handler).catch_unwind().await;
+            state.listening.store(false, Ordering::SeqCst);
+            let _ = done.send(result).await;
+        });
+
+        ServerHandle::new(host, self.local_addr.port(), state, task)
+    }
+}
+
+/// Wait until no message was received for `timeout`.
+async fn idle_watchdog(state: &State, timeout: Duration) {
+    state.touch();
+    loop {
+        let deadline = state
+            .last_activity
+            .lock()
+            .unwrap()
+            .unwrap_or_else(Instant::now)
+            + timeout;
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        task::sleep(deadline - now).await;
+    }
+    state.idle_timeout_fired.store(true, Ordering::SeqCst);
+}
+
+/// What woke up the loop serving a connection.
+enum Event {
+    /// A message, or the end of the stream, was received from the client.
+    Received(Option<Result<Message, WsError>>),
+    /// A message was pushed with `ServerHandle::send`, or relayed.
+    Pushed(Option<Pushed>),
+    /// A subscription has a message to stream.
+    Streaming,
+    /// A frame of the given reserved opcode was dropped by the transport,
+    /// see `MockServer::on_unsupported`.
+    Reserved(Option<u8>),
+}
+
+/// WebSocket stream of a served connection.
+pub(crate) type Socket<S = TcpStream> = WebSocketStream<Replay<S>>;
+
+/// Latency ramping linearly from `start` to `end` over `over`, once
+/// `elapsed`.
+fn ramp(start: Duration, end: Duration, over: Duration, elapsed: Duration) -> Duration {
+    if elapsed >= over {
+        return end;
+    }
+    let progress = elapsed.as_secs_f64() / over.as_secs_f64();
+    let (start, end) = (start.as_secs_f64(), end.as_secs_f64());
+    Duration::from_secs_f64(start + (end - start) * progress)
+}
+
+/// RNG of the connection `index`, derived from the `chaos_seed` `seed`.
+fn chaos_rng(seed: u64, index: usize) -> StdRng {
+    StdRng::seed_from_u64(seed ^ index as u64)
+}
+
+/// Split `text` in `chunks` pieces of about the same length, or in fewer
+/// pieces if it has fewer characters.
+fn split_text(text: &str, chunks: usize) -> Vec<&str> {
+    let mut pieces = Vec::with_capacity(chunks);
+    let mut rest = text;
+    for remaining in (1..=chunks).rev() {
+        let mut end = rest.len().div_ceil(remaining);
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        if end > 0 {
+            pieces.push(&rest[..end]);
+        }
+        rest = &rest[end..];
+    }
+    pieces
+}
+
+/// Parse a received text message as JSON, or as a JSON string if it is not
+/// valid JSON.
+fn parse_message(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn endpoint(host: &str, port: u16) -> String {
+        format!("ws://{}:{}", host, port)
+    }
+
+    /// Wait for the server to reach a state the client cannot synchronize with.
+    async fn eventually<F: Fn() -> bool>(condition: F) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached in time");
+    }
+
+    #[async_std::test]
+    #[should_panic]
+    async fn should_panic_if_same_port_used_twice() {
+        let _first = MockServer::default().port(8080).start().await.unwrap();
+        let _second = MockServer::default().port(8080).start().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn connect() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+
+        assert_eq!(server.host(), "localhost");
+        assert_ne!(server.port(), 0); // the port should be pick randomly by the OS
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn connect_with_custom_config() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .host("127.0.0.1".into())
+            .port(8080)
+            .start()
+            .await?;
+
+        assert_eq!(server.host(), "127.0.0.1");
+        assert_eq!(server.port(), 8080);
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_pong() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+
+        stream.send(Message::Ping("Some request".into())).await?;
+
+        let response = stream.next().await.unwrap()?;
+        assert_eq!(
+            response,
+            Message::Pong("Some request".into()),
+            "should answer with pong"
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_wait_for_close_message() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+
+        for _ in 0..10 {
+            stream.send(Message::Text("Some request".into())).await?;
+            let response = stream.next().await.unwrap()?;
+            assert_eq!(response, Message::Text("No more response".into()),);
+        }
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn connect_and_answer() -> Result<(), Box<dyn Error>> {
+        let mocked_responses = vec![
+            json!({"hello": "world"}),
+            json!({"hello": "france"}),
+            json!({"hello": "montpellier"}),
+        ];
+
+        let server = MockServer::default()
+            .responses(mocked_responses.clone())
+            .start()
+            .await?;
+
+        assert_eq!(server.host(), "localhost");
+        assert_ne!(server.port(), 0); // the port should be pick randomly by the OS
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+
+        for m_response in mocked_responses {
+            stream
+                .send(Message::Text(String::from("Trigger mocked server reply")))
+                .await?;
+
+            let response: Value =
+                serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+
+            assert_eq!(response, m_response);
+            assert_eq!(response["hello"], m_response["hello"]);
+        }
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_expose_query_params() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        assert!(server.query_params().is_empty());
+
+        let (mut stream, _) = async_tungstenite::async_std::connect_async(format!(
+            "{}/?token=abc&tag=a&tag=b%20c",
+            endpoint(server.host(), server.port())
+        ))
+        .await?;
+
+        // wait for the handshake to be processed by the server
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+
+        let params = server.query_params();
+        assert_eq!(params["token"], vec!["abc"]);
+        assert_eq!(params["tag"], vec!["a", "b c"]);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_according_to_query() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"hello": "world"})])
+            .when_query("token".into(), "abc".into(), json!({"auth": "ok"}))
+            .when_query("token".into(), "bad token".into(), json!({"auth": "ko"}))
+            .start()
+            .await?;
+
+        for (query, expected) in [
+            ("?token=abc", json!({"auth": "ok"})),
+            ("?token=bad%20token", json!({"auth": "ko"})),
+            ("", json!({"hello": "world"})),
+        ] {
+            let (mut stream, _) = async_tungstenite::async_std::connect_async(format!(
+                "{}/{}",
+                endpoint(server.host(), server.port()),
+                query
+            ))
+            .await?;
+
+            stream.send(Message::Text("Some request".into())).await?;
+            let response: Value =
+                serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+            assert_eq!(response, expected);
+
+            stream.close(None).await?;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_serve_http_routes() -> Result<(), Box<dyn Error>> {
+        use async_std::io::{ReadExt, WriteExt};
+        use async_std::net::TcpStream;
+
+        let server = MockServer::default()
+            .responses(vec![json!({"hello": "world"})])
+            .http_route(
+                "/health".into(),
+                200,
+                r#"{"status":"up"}"#.into(),
+                "application/json".into(),
+            )
+            .start()
+            .await?;
+
+        for (path, expected) in [
+            (
+                "/health?verbose=true",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 15\r\nConnection: close\r\n\r\n{\"status\":\"up\"}",
+            ),
+            (
+                "/unknown",
+                "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\nConnection: close\r\n\r\nNot Found",
+            ),
+        ] {
+            let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            assert_eq!(response, expected);
+        }
+
+        let (mut stream, _) = async_tungstenite::async_std::connect_async(format!(
+            "{}/health",
+            endpoint(server.host(), server.port())
+        ))
+        .await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        let response: Value = serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+        assert_eq!(response, json!({"hello": "world"}));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_send_response_headers() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .response_headers(vec![("X-Server-Version".into(), "1.2.3".into())])
+            .start()
+            .await?;
+
+        let (mut stream, response) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        assert_eq!(response.headers()["x-server-version"], "1.2.3");
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_not_override_handshake_headers() {
+        let error = MockServer::default()
+            .response_headers(vec![("Upgrade".into(), "h2c".into())])
+            .start()
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            error.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::ReservedHeader("Upgrade".into()))
+        );
+    }
+
+    #[async_std::test]
+    async fn should_drop_connections_randomly() -> Result<(), Box<dyn Error>> {
+        async fn outcomes(seed: u64) -> Result<Vec<bool>, Box<dyn Error>> {
+            let server = MockServer::default().flaky(0.5, seed).start().await?;
+            let mut outcomes = vec![];
+            for _ in 0..20 {
+                match async_tungstenite::async_std::connect_async(endpoint(
+                    server.host(),
+                    server.port(),
+                ))
+                .await
+                {
+                    Ok((mut stream, _)) => {
+                        stream.send(Message::Text("Some request".into())).await?;
+                        stream.next().await.unwrap()?;
+                        stream.close(None).await?;
+                        outcomes.push(true);
+                    }
+                    Err(_) => outcomes.push(false),
+                }
+            }
+
+            let served = outcomes.iter().filter(|served| **served).count();
+            assert_eq!(server.served_connections(), served);
+            assert_eq!(server.dropped_connections(), 20 - served);
+            Ok(outcomes)
+        }
+
+        let first_run = outcomes(42).await?;
+        assert!(first_run.contains(&true));
+        assert!(first_run.contains(&false));
+        assert_eq!(outcomes(42).await?, first_run, "should be reproducible");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_invalid_drop_probability() {
+        let error = MockServer::default()
+            .flaky(1.5, 42)
+            .start()
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            error.downcast_ref::<ConfigError>(),
+            Some(&ConfigError::InvalidProbability(1.5))
+        );
+    }
+
+    #[async_std::test]
+    async fn should_record_received_messages() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+
+        for connection in 0..2 {
+            let (mut stream, _) =
+                async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                    .await?;
+            stream.send(Message::Text("Some request".into())).await?;
+            stream.next().await.unwrap()?;
+            stream.send(Message::Binary(vec![connection as u8])).await?;
+            stream.send(Message::Ping(vec![])).await?;
+            stream.next().await.unwrap()?;
+            stream.close(None).await?;
+        }
+
+        assert_eq!(
+            server.received_messages(),
+            vec![
+                RecordedMessage {
+                    connection: 0,
+                    message: Message::Text("Some request".into())
+                },
+                RecordedMessage {
+                    connection: 0,
+                    message: Message::Binary(vec![0])
+                },
+                RecordedMessage {
+                    connection: 1,
+                    message: Message::Text("Some request".into())
+                },
+                RecordedMessage {
+                    connection: 1,
+                    message: Message::Binary(vec![1])
+                },
+            ]
+        );
+        server.assert_no_extra_messages(4);
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "expected at most 1 messages but received 2")]
+    async fn should_panic_on_extra_messages() {
+        let server = MockServer::default().start().await.unwrap();
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await
+                .unwrap();
+
+        for _ in 0..2 {
+            stream
+                .send(Message::Text("Some request".into()))
+                .await
+                .unwrap();
+            stream.next().await.unwrap().unwrap();
+        }
+
+        server.assert_no_extra_messages(1);
+    }
+
+    #[async_std::test]
+    async fn should_delay_connections_acceptance() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .backlog(16)
+            .accept_delay(Duration::from_millis(200))
+            .start()
+            .await?;
+
+        let started = std::time::Instant::now();
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_push_messages_out_of_band() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+
+        // buffered until a client connects
+        server.send(MockResponse::Json(json!({"event": "welcome"})))?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"event": "welcome"}).to_string())
+        );
+
+        server.send(MockResponse::Binary(vec![1, 2, 3]))?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Binary(vec![1, 2, 3])
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_out_of_band_messages_without_client() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .pending_sends(PendingSends::Reject)
+            .start()
+            .await?;
+
+        assert_eq!(
+            server.send(MockResponse::Text("hello".into())),
+            Err(SendError::NoActiveConnection)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_record_pongs() -> Result<(), Box<dyn Error>> {
+        let pongs = Arc::new(std::sync::Mutex::new(vec![]));
+        let callback_pongs = pongs.clone();
+        let server = MockServer::default()
+            .on_pong(move |payload| callback_pongs.lock().unwrap().push(payload.to_vec()))
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        let before = Instant::now();
+        stream.send(Message::Pong("heartbeat".into())).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+
+        let received = server.received_pongs();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].connection, 0);
+        assert_eq!(received[0].payload, b"heartbeat".to_vec());
+        assert!(received[0].received_at >= before);
+        assert_eq!(*pongs.lock().unwrap(), vec![b"heartbeat".to_vec()]);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_respond_every_nth_message() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"batch": 1}), json!({"batch": 2})])
+            .respond_every(3)
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        for batch in 1..=2 {
+            for _ in 0..3 {
+                stream.send(Message::Text("Some request".into())).await?;
+            }
+            let response: Value =
+                serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+            assert_eq!(response, json!({ "batch": batch }));
+        }
+        assert_eq!(server.received_messages().len(), 6);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_respond_every_zero() {
+        let error = MockServer::default()
+            .respond_every(0)
+            .start()
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "respond_every",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "unexpected response to request #1")]
+    async fn should_detect_unexpected_conversation_response() {
+        let server = MockServer::default()
+            .expect_conversation(vec![
+                (json!({"action": "login"}), json!({"status": "logged"})),
+                (json!({"action": "subscribe"}), json!({"status": "error"})),
+            ])
+            .start()
+            .await
+            .unwrap();
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await
+                .unwrap();
+        run_conversation(
+            &mut stream,
+            &[
+                (json!({"action": "login"}), json!({"status": "logged"})),
+                (
+                    json!({"action": "subscribe"}),
+                    json!({"status": "subscribed"}),
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+    }
+
+    #[async_std::test]
+    async fn should_survive_failing_connections() -> Result<(), Box<dyn Error>> {
+        use async_std::io::WriteExt;
+
+        let server = MockServer::default().start().await?;
+
+        // invalid handshake
+        let mut stream = async_std::net::TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n")
+            .await?;
+        drop(stream);
+
+        // connection dropped without closing handshake
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+        drop(stream);
+        // connections are served concurrently, wait for this one to be recorded first
+        eventually(|| server.disconnections().len() == 1).await;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+        stream.close(None).await?;
+
+        eventually(|| server.disconnections().len() == 2).await;
+        let disconnections = server.disconnections();
+        assert_eq!(disconnections.len(), 2);
+        assert!(matches!(
+            disconnections[0],
+            Disconnection {
+                connection: 0,
+                reason: DisconnectReason::Error(_)
+            }
+        ));
+        assert_eq!(
+            disconnections[1],
+            Disconnection {
+                connection: 1,
+                reason: DisconnectReason::Closed
+            }
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_respond_asynchronously() -> Result<(), Box<dyn Error>> {
+        let (sender, receiver) = async_std::channel::unbounded::<Value>();
+        let server = MockServer::default()
+            .async_respond(move |request| {
+                let request = request.clone();
+                let receiver = receiver.clone();
+                Box::pin(async move {
+                    let value = receiver.recv().await.unwrap();
+                    json!({"request": request, "value": value})
+                })
+            })
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        stream
+            .send(Message::Text(json!({"id": 1}).to_string()))
+            .await?;
+        stream.send(Message::Text("not json".into())).await?;
+        sender.send(json!("first")).await?;
+        sender.send(json!("second")).await?;
+
+        let response: Value = serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+        assert_eq!(response, json!({"request": {"id": 1}, "value": "first"}));
+        let response: Value = serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+        assert_eq!(response, json!({"request": "not json", "value": "second"}));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_large_messages() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"hello": "world"})])
+            .reject_large_messages(8, json!({"error": "too large"}))
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(endpoint(server.host(), server.port()))
+                .await?;
+        for message in [
+            Message::Text("Some large request".into()),
+            Message::Binary(vec![0; 9]),
+            Message::Text("request".into()),
+        ] {
+            stream.send(message).await?;
+        }
+
+        for expected in [
+            json!({"error": "too large"}),
+            json!({"error": "too large"}),
+            json!({"hello": "world"}),
+        ] {
+            let response: Value =
+                serde_json::from_str(&stream.next().await.unwrap()?.into_text()?)?;
+            assert_eq!(response, expected);
+        }
+        assert_eq!(server.oversized_messages(), 2);
+        assert_eq!(server.received_messages().len(), 3);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_follow_scenario_per_connection() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .scenario(
+                Scenario::new("init")
+                    .state("init")
+                    .on(json!({"action": "auth"}))
+                    .respond(json!({"auth": "ok"}))
+                    .goto("authenticated")
+                    .state("authenticated")
+                    .on(json!({"action": "logout"}))
+                    .goto("init"),
+            )
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        run_conversation(
+            &mut stream,
+            &[(json!({"action": "auth"}), json!({"auth": "ok"}))],
+        )
+        .await?;
+        assert_eq!(server.scenario_state(0), Some("authenticated".into()));
+
+        // silent transition
+        stream
+            .send(Message::Text(json!({"action": "logout"}).to_string()))
+            .await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text("No more response".into())
+        );
+        assert_eq!(server.scenario_state(0), Some("init".into()));
+        stream.close(None).await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+        assert_eq!(server.scenario_state(1), Some("init".into()));
+        assert_eq!(server.scenario_state(2), None);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_send_compressed_frames() -> Result<(), Box<dyn Error>> {
+        use async_std::io::ReadExt;
+        use flate2::{Decompress, FlushDecompress};
+
+        let server = MockServer::default()
+            .responses(vec![MockResponse::compressed(json!({"hello": "world"}))])
+            .start()
+            .await?;
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+
+        let mut header = [0; 2];
+        stream.get_mut().read_exact(&mut header).await?;
+        assert_eq!(
+            header[0], 0xC1,
+            "should be a final text frame with RSV1 set"
+        );
+        let mut payload = vec![0; header[1] as usize];
+        stream.get_mut().read_exact(&mut payload).await?;
+
+        payload.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let mut text = Vec::with_capacity(1024);
+        Decompress::new(false).decompress_vec(&payload, &mut text, FlushDecompress::Sync)?;
+        assert_eq!(
+            serde_json::from_slice::<Value>(&text)?,
+            json!({"hello": "world"})
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_health_check() -> Result<(), Box<dyn Error>> {
+        use async_std::io::{ReadExt, WriteExt};
+
+        async fn health_status(server: &ServerHandle) -> Result<String, Box<dyn Error>> {
+            let mut stream =
+                async_std::net::TcpStream::connect((server.host(), server.port())).await?;
+            stream
+                .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            Ok(response.lines().next().unwrap_or_default().into())
+        }
+
+        let server = MockServer::default().health_check(true).start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        assert_eq!(health_status(&server).await?, "HTTP/1.1 200 OK");
+
+        server.drain();
+        assert_eq!(
+            health_status(&server).await?,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        assert!(
+            async_tungstenite::async_std::connect_async(server.endpoint())
+                .await
+                .is_err()
+        );
+        assert_eq!(server.handshake_statuses(), vec![101, 503]);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reset_connection_after_responses() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite;
+
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1}), json!({"id": 2})])
+            .reset_after(1)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"id": 1}).to_string())
+        );
+        match stream.next().await {
+            Some(Err(tungstenite::Error::Io(error))) => {
+                assert_eq!(error.kind(), std::io::ErrorKind::ConnectionReset)
+            }
+            other => panic!("expected a connection reset, got {:?}", other),
+        }
+
+        eventually(|| !server.disconnections().is_empty()).await;
+        assert_eq!(
+            server.disconnections(),
+            vec![Disconnection {
+                connection: 0,
+                reason: DisconnectReason::Reset
+            }]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_stream_subscriptions() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .subscription("prices".into(), vec![json!(1), json!(2)])
+            .subscription("news".into(), vec![json!("hello")])
+            .responses(vec![json!({"status": "ok"})])
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for id in &["prices", "news"] {
+            stream
+                .send(Message::Text(json!({ "subscribe": id }).to_string()))
+                .await?;
+        }
+        let mut streamed = vec![];
+        for _ in 0..3 {
+            let message = stream.next().await.unwrap()?;
+            streamed.push(serde_json::from_str::<Value>(&message.into_text()?)?);
+        }
+        let data = |id: &str| -> Vec<Value> {
+            streamed
+                .iter()
+                .filter(|message| message["subscription"] == id)
+                .map(|message| message["data"].clone())
+                .collect()
+        };
+        assert_eq!(data("prices"), vec![json!(1), json!(2)]);
+        assert_eq!(data("news"), vec![json!("hello")]);
+        eventually(|| server.active_subscriptions(0).len() == 2).await;
+        assert_eq!(server.active_subscriptions(0), vec!["prices", "news"]);
+
+        stream
+            .send(Message::Text(json!({"unsubscribe": "prices"}).to_string()))
+            .await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"status": "ok"}).to_string())
+        );
+        assert_eq!(server.active_subscriptions(0), vec!["news"]);
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_apply_exhaust_behavior() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .on_exhausted(ExhaustBehavior::Echo)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        for expected in &[json!({"id": 1}).to_string(), "Some request".into()] {
+            stream.send(Message::Text("Some request".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(expected.clone())
+            );
+        }
+        stream.close(None).await?;
+
+        let server = MockServer::default()
+            .on_exhausted(ExhaustBehavior::Close(CloseCode::Away))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        match stream.next().await.unwrap()? {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_stamp_responses_with_clock() -> Result<(), Box<dyn Error>> {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_633_089_600);
+        let server = MockServer::default()
+            .responses(vec![json!({"event": "tick", "at": "{now}"})])
+            .clock(move || now)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"event": "tick", "at": "2021-10-01T12:00:00.000Z"}).to_string())
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_limit_bandwidth() -> Result<(), Box<dyn Error>> {
+        let response = MockResponse::Binary(vec![0; 2000]);
+        let server = MockServer::default()
+            .responses(vec![response.clone()])
+            .bandwidth_limit(10_000)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let sent_at = Instant::now();
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, response.to_message());
+        assert!(sent_at.elapsed() >= Duration::from_millis(150));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_zero_bandwidth_limit() {
+        let error = MockServer::default()
+            .bandwidth_limit(0)
+            .start()
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "bandwidth_limit",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    async fn should_truncate_handshake() -> Result<(), Box<dyn Error>> {
+        use async_std::io::ReadExt;
+
+        let server = MockServer::default().truncate_handshake(20).start().await?;
+        let mut stream = async_std::net::TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        assert_eq!(response, b"HTTP/1.1 101 Switchi");
+
+        assert!(
+            async_tungstenite::async_std::connect_async(server.endpoint())
+                .await
+                .is_err()
+        );
+        assert_eq!(server.truncated_handshakes(), 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_log_matched_rules() -> Result<(), Box<dyn Error>> {
+        let scenario = Scenario::new("init")
+            .state("init")
+            .on(json!({"action": "auth"}))
+            .name("auth")
+            .respond(json!({"auth": "ok"}));
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .scenario(scenario)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let auth = json!({"action": "auth"}).to_string();
+        for request in &[auth.as_str(), "first", "second"] {
+            stream.send(Message::Text(request.to_string())).await?;
+            stream.next().await.unwrap()?;
+        }
+
+        let entry = |message: &str, rule| MatchLogEntry {
+            connection: 0,
+            message: message.into(),
+            rule,
+        };
+        assert_eq!(
+            server.match_log(),
+            vec![
+                entry(
+                    &auth,
+                    MatchedRule::Scenario {
+                        state: "init".into(),
+                        rule: 0,
+                        name: Some("auth".into())
+                    }
+                ),
+                entry("first", MatchedRule::Queue),
+                entry("second", MatchedRule::Exhausted),
+            ]
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_close_on_oversized_ping() -> Result<(), Box<dyn Error>> {
+        use async_std::io::WriteExt;
+        use async_tungstenite::tungstenite::protocol::frame::Frame;
+
+        let server = MockServer::default()
+            .strict_control_frames(true)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let mut ping = Frame::ping(vec![0; 126]);
+        ping.header_mut().mask = Some([1, 2, 3, 4]);
+        let mut bytes = vec![];
+        ping.format(&mut bytes)?;
+        stream.get_mut().write_all(&bytes).await?;
+
+        match stream.next().await.unwrap()? {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Protocol),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert_eq!(server.control_frame_violations(), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_queue_connections_over_concurrency_limit() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1}), json!({"id": 2})])
+            .concurrency_limit(1)
+            .start()
+            .await?;
+        let (mut first, _) = async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let endpoint = server.endpoint();
+        let second = task::spawn(async move {
+            async_tungstenite::async_std::connect_async(endpoint)
+                .await
+                .map(|(stream, _)| stream)
+                .map_err(|error| error.to_string())
+        });
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(server.served_connections(), 1);
+        assert_eq!(server.in_flight_connections(), 1);
+
+        first.close(None).await?;
+        let mut second = second.await?;
+        second.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            second.next().await.unwrap()?,
+            Message::Text(json!({"id": 1}).to_string())
+        );
+        assert_eq!(server.served_connections(), 2);
+
+        second.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_interpolate_env_in_responses() -> Result<(), Box<dyn Error>> {
+        std::env::set_var("SURIMI_TEST_ENDPOINT", "wss://example.com");
+        let server = MockServer::default()
+            .responses(vec![
+                json!({"endpoint": "${SURIMI_TEST_ENDPOINT}/feed"}),
+                json!({"missing": "${SURIMI_TEST_MISSING}"}),
+            ])
+            .interpolate_env(true)
+            .missing_env(MissingEnv::Fail)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"endpoint": "wss://example.com/feed"}).to_string())
+        );
+        stream.send(Message::Text("Some request".into())).await?;
+        assert!(!matches!(stream.next().await, Some(Ok(Message::Text(_)))));
+
+        eventually(|| !server.disconnections().is_empty()).await;
+        assert_eq!(
+            server.disconnections()[0].reason,
+            DisconnectReason::Error("environment variable SURIMI_TEST_MISSING is not set".into())
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_close_everything_after_global_idle_timeout() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .global_idle_timeout(Duration::from_millis(200))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        task::sleep(Duration::from_millis(100)).await;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+        task::sleep(Duration::from_millis(150)).await;
+        assert!(!server.idle_timeout_fired());
+
+        match stream.next().await.unwrap()? {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert!(server.idle_timeout_fired());
+        assert!(
+            async_tungstenite::async_std::connect_async(server.endpoint())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_record_client_close_frame() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream
+            .close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "done".into(),
+            }))
+            .await?;
+        eventually(|| server.client_close_frame().is_some()).await;
+        assert_eq!(
+            server.client_close_frame(),
+            Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "done".into()
+            })
+        );
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        eventually(|| server.client_close_frame().is_none()).await;
+        assert_eq!(server.client_close_frame(), None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_delay_pongs() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .pong_delay(Duration::from_millis(100))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let sent_at = Instant::now();
+        stream.send(Message::Ping(b"ping".to_vec())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Pong(b"ping".to_vec())
+        );
+        assert!(sent_at.elapsed() >= Duration::from_millis(100));
+
+        eventually(|| !server.received_pings().is_empty()).await;
+        let pings = server.received_pings();
+        assert_eq!(pings.len(), 1);
+        assert_eq!(pings[0].payload, b"ping");
+        assert!(pings[0].ponged_at.unwrap() - pings[0].received_at >= Duration::from_millis(100));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_parse_string_responses() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses_str(vec![r#"{ "hello":  "world" }"#])
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(r#"{"hello":"world"}"#.into())
+        );
+        stream.close(None).await?;
+
+        let error = MockServer::default()
+            .responses_str(vec!["{}", "{oops"])
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidJson { index: 1, .. })
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_out_of_order() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"n": 1}), json!({"n": 2}), json!({"n": 3})])
+            .out_of_order(true)
+            .out_of_order_batch(3, 42)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for id in 1..=3 {
+            stream
+                .send(Message::Text(json!({ "id": id }).to_string()))
+                .await?;
+        }
+        let mut received = vec![];
+        for _ in 0..3 {
+            let message = stream.next().await.unwrap()?;
+            received.push(serde_json::from_str::<Value>(&message.into_text()?)?);
+        }
+        assert_eq!(
+            received,
+            vec![
+                json!({"n": 3, "id": 3}),
+                json!({"n": 2, "id": 2}),
+                json!({"n": 1, "id": 1}),
+            ]
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_call_on_shutdown_with_cause() -> Result<(), Box<dyn Error>> {
+        let causes = Arc::new(Mutex::new(vec![]));
+        let recorded = causes.clone();
+        let server = MockServer::default()
+            .on_shutdown(move |cause| recorded.lock().unwrap().push(cause))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        server.stop();
+        match stream.next().await.unwrap()? {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        eventually(|| !causes.lock().unwrap().is_empty()).await;
+        assert_eq!(*causes.lock().unwrap(), vec![ShutdownCause::Explicit]);
+
+        let causes = Arc::new(Mutex::new(vec![]));
+        let recorded = causes.clone();
+        let _server = MockServer::default()
+            .lifetime(Duration::from_millis(50))
+            .on_shutdown(move |cause| recorded.lock().unwrap().push(cause))
+            .start()
+            .await?;
+        eventually(|| !causes.lock().unwrap().is_empty()).await;
+        assert_eq!(*causes.lock().unwrap(), vec![ShutdownCause::Lifetime]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_repeat_responses() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses_repeated(vec![
+                (2, json!({"status": "pending"})),
+                (0, json!({"status": "skipped"})),
+                (1, json!({"status": "done"})),
+            ])
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for status in &["pending", "pending", "done"] {
+            stream.send(Message::Text("poll".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({ "status": status }).to_string())
+            );
+        }
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_feed_raw_bytes() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+        use async_tungstenite::tungstenite::protocol::frame::Frame;
+
+        let server = MockServer::default().responses(vec![json!({"id": 1})]);
+        let mut input = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        for mut frame in [
+            Frame::message(b"Some request".to_vec(), OpCode::Data(Data::Text), true),
+            Frame::close(None),
+        ] {
+            frame.header_mut().mask = Some([1, 2, 3, 4]);
+            frame.format(&mut input)?;
+        }
+
+        let output = String::from_utf8_lossy(&server.feed_raw(&input).await?).into_owned();
+        assert!(output.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(output.contains(&json!({"id": 1}).to_string()));
+
+        assert!(server.feed_raw(b"\x00garbage").await?.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_serve_an_in_memory_connection() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default().responses(vec![
+            MockResponse::from(json!({"id": 1})),
+            MockResponse::compressed(json!({"id": 2})),
+        ]);
+        let state = State::default();
+        let mut connection = MockConnection::new(vec![
+            Message::Text("first".into()),
+            Message::Text("second".into()),
+            Message::Text("third".into()),
+        ]);
+
+        let reason = server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        assert_eq!(reason, DisconnectReason::Closed);
+        assert_eq!(
+            connection.sent,
+            vec![
+                Message::Text(json!({"id": 1}).to_string()),
+                Message::Text("No more response".into()),
+            ]
+        );
+        assert_eq!(connection.frames.len(), 1);
+        assert!(connection.frames[0].header().rsv1);
+        assert_eq!(state.received_messages.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_ignore_undersized_requests() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        for (accumulate, expected) in [
+            (false, vec!["0123456789"]),
+            (true, vec!["0123401234", "0123456789"]),
+        ] {
+            let server = MockServer::default()
+                .on_exhausted(ExhaustBehavior::Echo)
+                .min_request_bytes(8)
+                .accumulate_undersized(accumulate);
+            let state = State::default();
+            let mut connection = MockConnection::new(
+                ["01234", "01234", "0123456789"]
+                    .iter()
+                    .map(|text| Message::Text(text.to_string())),
+            );
+
+            server
+                .serve_connection(
+                    &mut connection,
+                    0,
+                    &state,
+                    state.connect(0),
+                    server.responses.clone().into(),
+                    None,
+                )
+                .await?;
+            let sent: Vec<_> = connection.sent.iter().map(Message::to_string).collect();
+            assert_eq!(sent, expected);
+            let undersized = state.undersized_messages.lock().unwrap().len();
+            assert_eq!(undersized, if accumulate { 1 } else { 2 });
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_per_client_responses() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut map = HashMap::new();
+        map.insert("acme".to_string(), vec![json!({"tenant": "acme"})]);
+        let server = MockServer::default()
+            .responses(vec![json!({"tenant": "default"})])
+            .responses_by(
+                |request| {
+                    request
+                        .headers()
+                        .get("X-Tenant")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string()
+                },
+                map,
+            )
+            .start()
+            .await?;
+
+        for (tenant, expected) in [("acme", "acme"), ("other", "default")] {
+            let mut request = server.endpoint().into_client_request()?;
+            request.headers_mut().insert("X-Tenant", tenant.parse()?);
+            let (mut stream, _) = async_tungstenite::async_std::connect_async(request).await?;
+            stream.send(Message::Text("hello".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({ "tenant": expected }).to_string())
+            );
+            stream.close(None).await?;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_require_websocket_version() -> Result<(), Box<dyn Error>> {
+        use async_std::io::ReadExt;
+
+        let server = MockServer::default().require_version(13).start().await?;
+        let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 8\r\n\r\n",
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        assert_eq!(server.offered_versions(), vec![Some(8), Some(13)]);
+        assert_eq!(server.handshake_statuses(), vec![426, 101]);
+
+        let error = MockServer::default()
+            .require_version(8)
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "require_version",
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_send_batched_responses_in_one_frame() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .batch_responses(vec![vec![json!({"id": 1}), json!({"id": 2})], vec![]], "\n")
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for expected in &["{\"id\":1}\n{\"id\":2}", ""] {
+            stream.send(Message::Text("hello".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(expected.to_string())
+            );
+        }
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_close_after_delay() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .close_delay(Duration::from_millis(100))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let closed_at = Instant::now();
+        stream
+            .close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            }))
+            .await?;
+        assert!(matches!(stream.next().await, Some(Ok(Message::Close(_)))));
+        assert!(closed_at.elapsed() >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_respond_by_total_count() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .respond_by_total_count(|count| json!({ "count": count }).into())
+            .start()
+            .await?;
+
+        for expected in 1..=4 {
+            let (mut stream, _) =
+                async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+            stream.send(Message::Text("hello".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({ "count": expected }).to_string())
+            );
+            stream.close(None).await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn should_list_configured_responses_in_order() {
+        let server = MockServer::default().responses(vec![json!(1), json!(2), json!(3)]);
+        assert_eq!(
+            server.configured_responses(),
+            [json!(1).into(), json!(2).into(), json!(3).into()]
+        );
+    }
+
+    #[async_std::test]
+    async fn should_stall_in_the_middle_of_responses() -> Result<(), Box<dyn Error>> {
+        let response = MockResponse::Text("a".repeat(1000));
+        let server = MockServer::default()
+            .responses(vec![MockResponse::Text("small".into()), response.clone()])
+            .write_stall(100, Duration::from_millis(150))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let sent_at = Instant::now();
+        stream.send(Message::Text("first".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Text("small".into()));
+        assert!(sent_at.elapsed() < Duration::from_millis(150));
+
+        stream.send(Message::Text("second".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, response.to_message());
+        assert!(sent_at.elapsed() >= Duration::from_millis(150));
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_redirect_the_first_connections() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::Error as WsError;
+
+        let server = MockServer::default()
+            .redirect(307, "ws://elsewhere:9000/".into())
+            .redirect_count(2)
+            .start()
+            .await?;
+
+        for _ in 0..2 {
+            match async_tungstenite::async_std::connect_async(server.endpoint()).await {
+                Err(WsError::Http(response)) => {
+                    assert_eq!(response.status(), 307);
+                    assert_eq!(response.headers()["Location"], "ws://elsewhere:9000/");
+                }
+                other => panic!("expected a redirect, got {:?}", other.map(|_| ())),
+            }
+        }
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        assert_eq!(server.redirects(), 2);
+        assert_eq!(server.handshake_statuses(), vec![307, 307, 101]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_unsupported_redirect_status() {
+        let error = MockServer::default()
+            .redirect(200, "ws://elsewhere/".into())
+            .start()
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "redirect",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    async fn should_pause_accepting_connections() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!(1), json!(2)])
+            .start()
+            .await?;
+        let (mut connected, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        server.pause_accept();
+        let attempt = async_std::future::timeout(
+            Duration::from_millis(200),
+            Box::pin(async_tungstenite::async_std::connect_async(
+                server.endpoint(),
+            )),
+        )
+        .await;
+        assert!(attempt.is_err());
+        connected.send(Message::Text("hello".into())).await?;
+        assert_eq!(connected.next().await.unwrap()?, Message::Text("1".into()));
+
+        server.resume_accept();
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        connected.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_split_json_responses() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .responses(vec![json!({"name": "surimi"}), json!(1)])
+            .split_json(3);
+        let state = State::default();
+        let mut connection = MockConnection::new(vec![
+            Message::Text("first".into()),
+            Message::Text("second".into()),
+        ]);
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        let sent: Vec<_> = connection.sent.iter().map(Message::to_string).collect();
+        assert_eq!(sent, ["{\"name", "\":\"sur", "imi\"}", "1"]);
+        assert_eq!(split_text("é€a", 2), ["é€", "a"]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_spawn_tasks_with_custom_spawner() -> Result<(), Box<dyn Error>> {
+        use std::sync::atomic::AtomicUsize;
+
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let counter = spawned.clone();
+        let server = MockServer::default()
+            .responses(vec![json!(1)])
+            .spawner(move |future| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                task::spawn(future);
+            })
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("hello".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Text("1".into()));
+        stream.close(None).await?;
+
+        // the accept loop and the connection
+        assert_eq!(spawned.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_wait_for_matching_message() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("hello".into())).await?;
+
+        let is_subscribe = |message: &Message| message.to_string().contains("subscribe");
+        let client = task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            stream
+                .send(Message::Text(r#"{"subscribe": "a"}"#.into()))
+                .await?;
+            stream.close(None).await
+        });
+        let recorded = server
+            .wait_for(is_subscribe, Duration::from_secs(5))
+            .await?;
+        assert_eq!(
+            recorded.message,
+            Message::Text(r#"{"subscribe": "a"}"#.into())
+        );
+        client.await?;
+
+        assert_eq!(
+            server
+                .wait_for(|message| message.is_binary(), Duration::from_millis(50))
+                .await,
+            Err(WaitError::Timeout)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_set_reserved_bits() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!(1)])
+            .set_rsv(0b010)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("hello".into())).await?;
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(WsError::Protocol(ProtocolError::NonZeroReservedBits)))
+        ));
+
+        eventually(|| server.rsv_frames().len() == 1).await;
+        assert_eq!(server.rsv_frames()[0].message, Message::Text("1".into()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_seed_chaos_per_connection() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let ids: Vec<usize> = (0..8).collect();
+        let server = MockServer::default()
+            .responses(ids.iter().map(|_| json!({})).collect())
+            .out_of_order(true)
+            .out_of_order_batch(ids.len(), 0)
+            .chaos_seed(7);
+
+        let mut orders = vec![];
+        for connection in 0..2 {
+            let state = State::default();
+            let mut socket = MockConnection::new(
+                ids.iter()
+                    .map(|id| Message::Text(json!({ "id": id }).to_string())),
+            );
+            server
+                .serve_connection(
+                    &mut socket,
+                    connection,
+                    &state,
+                    state.connect(connection),
+                    server.responses.clone().into(),
+                    None,
+                )
+                .await?;
+            let order: Vec<Value> = socket
+                .sent
+                .iter()
+                .map(|message| {
+                    serde_json::from_str::<Value>(&message.to_string()).unwrap()["id"].clone()
+                })
+                .collect();
+
+            let mut expected: Vec<Value> = ids.iter().map(|id| json!(id)).collect();
+            expected.shuffle(&mut StdRng::seed_from_u64(7 ^ connection as u64));
+            assert_eq!(order, expected);
+            orders.push(order);
+        }
+        assert_ne!(orders[0], orders[1]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_stop_ponging_after_pong_count() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!(1)])
+            .pong_count(2)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for payload in [b"1", b"2", b"3"] {
+            stream.send(Message::Ping(payload.to_vec())).await?;
+        }
+        stream.send(Message::Text("hello".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Pong(b"1".to_vec()));
+        assert_eq!(stream.next().await.unwrap()?, Message::Pong(b"2".to_vec()));
+        assert_eq!(stream.next().await.unwrap()?, Message::Text("1".into()));
+
+        stream.close(None).await?;
+        let pings = server.received_pings();
+        assert_eq!(pings.len(), 3);
+        assert!(pings[2].ponged_at.is_none());
+        assert_eq!(server.sent_pongs(), 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_always_respond_the_same() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .always_respond(json!({"status": "ok"}))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for n in 0..100 {
+            let message = if n % 2 == 0 {
+                Message::Text(n.to_string())
+            } else {
+                Message::Binary(vec![n])
+            };
+            stream.send(message).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({"status": "ok"}).to_string())
+            );
+        }
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_join_the_server_task() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        server.stop();
+        async_std::future::timeout(Duration::from_secs(5), server.join()).await?;
+        assert!(matches!(stream.next().await, Some(Ok(Message::Close(_)))));
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "on_shutdown failed")]
+    async fn should_resume_server_task_panics() {
+        let server = MockServer::default()
+            .on_shutdown(|_| panic!("on_shutdown failed"))
+            .start()
+            .await
+            .unwrap();
+        server.stop();
+        server.join().await;
+    }
+
+    #[async_std::test]
+    async fn should_reorder_responses() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .responses((1..=5).map(|n| json!({ "seq": n })).collect())
+            .reorder(3, 7)
+            .on_exhausted(ExhaustBehavior::Close(CloseCode::Normal));
+        let state = State::default();
+        let mut connection = MockConnection::new((0..6).map(|n| Message::Text(n.to_string())));
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        let sequence: Vec<_> = connection.sent[..5]
+            .iter()
+            .map(|message| {
+                serde_json::from_str::<Value>(message.to_text().unwrap()).unwrap()["seq"].clone()
+            })
+            .collect();
+        let (mut first, mut rest) = (sequence[..3].to_vec(), sequence[3..].to_vec());
+        first.sort_by_key(|seq| seq.as_u64());
+        rest.sort_by_key(|seq| seq.as_u64());
+        // full windows are shuffled, and the rest is flushed before closing
+        assert_eq!(first, vec![json!(1), json!(2), json!(3)]);
+        assert_eq!(rest, vec![json!(4), json!(5)]);
+        assert_ne!(sequence, (1..=5).map(|n| json!(n)).collect::<Vec<_>>());
+        assert!(matches!(connection.sent[5], Message::Close(_)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_compare_received_messages_with_snapshot() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("surimi-snapshot-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        for (request, matching) in [("hello", true), ("hello", true), ("bye", false)] {
+            let server = MockServer::default()
+                .on_exhausted(ExhaustBehavior::Echo)
+                .snapshot(&path)
+                .start()
+                .await?;
+            let (mut stream, _) =
+                async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+            stream.send(Message::Text(request.into())).await?;
+            stream.next().await.unwrap()?;
+            stream.close(None).await?;
+
+            server.stop();
+            let joined = AssertUnwindSafe(server.join()).catch_unwind().await;
+            assert_eq!(joined.is_ok(), matching);
+        }
+        assert_eq!(std::fs::read_to_string(&path)?, "0 text \"hello\"\n");
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_close_abnormally_after_responses() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, error::ProtocolError};
+
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1}), json!({"id": 2})])
+            .abnormal_close_after(1)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("Some request".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"id": 1}).to_string())
+        );
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(tungstenite::Error::Protocol(
+                ProtocolError::ResetWithoutClosingHandshake
+            )))
+        ));
+
+        eventually(|| !server.disconnections().is_empty()).await;
+        assert_eq!(
+            server.disconnections(),
+            vec![Disconnection {
+                connection: 0,
+                reason: DisconnectReason::Abnormal
+            }]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_record_connection_stats() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .on_exhausted(ExhaustBehavior::Echo)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        let local_addr = stream.get_ref().local_addr()?;
+
+        for request in ["first", "second"] {
+            stream.send(Message::Text(request.into())).await?;
+            stream.next().await.unwrap()?;
+        }
+        stream.send(Message::Ping(vec![])).await?;
+        stream.close(None).await?;
+        while stream.next().await.is_some() {}
+
+        eventually(|| !server.connection_stats().is_empty()).await;
+        let stats = &server.connection_stats()[0];
+        assert_eq!(stats.connection, 0);
+        assert_eq!(stats.peer, Some(local_addr));
+        assert_eq!((stats.messages_sent, stats.messages_received), (2, 2));
+        // the handshake, then the masked text, ping and close frames
+        let frames = (6 + "first".len() + 6 + "second".len() + 6 + 6) as u64;
+        assert!(stats.bytes_received > frames);
+        // the handshake, then the text, pong and close frames
+        let frames = (2 + json!({"id": 1}).to_string().len() + 2 + "second".len() + 2 + 2) as u64;
+        assert!(stats.bytes_sent > frames);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_handshakes_with_statuses() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite;
+
+        let server = MockServer::default()
+            .handshake_statuses(vec![503, 429])
+            .start()
+            .await?;
+
+        for expected in [503, 429] {
+            match async_tungstenite::async_std::connect_async(server.endpoint()).await {
+                Err(tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), expected)
+                }
+                other => panic!("expected a {} response, got {:?}", expected, other),
+            }
+        }
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+
+        assert_eq!(server.handshake_statuses(), vec![503, 429, 101]);
+        eventually(|| server.served_connections() == 1).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_invalid_handshake_statuses() {
+        let error = MockServer::default()
+            .handshake_statuses(vec![503, 1000])
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "handshake_statuses",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    async fn should_drop_responses_randomly() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        async fn answered(seed: u64) -> Result<Vec<Message>, Box<dyn Error>> {
+            let server = MockServer::default()
+                .on_exhausted(ExhaustBehavior::Echo)
+                .response_drop_rate(0.5, seed);
+            let state = State::default();
+            let mut connection = MockConnection::new((0..20).map(|n| Message::Text(n.to_string())));
+            server
+                .serve_connection(
+                    &mut connection,
+                    0,
+                    &state,
+                    state.connect(0),
+                    server.responses.clone().into(),
+                    None,
+                )
+                .await?;
+
+            assert_eq!(state.received_messages.lock().unwrap().len(), 20);
+            let dropped = state.dropped_responses.load(Ordering::SeqCst);
+            assert_eq!(connection.sent.len(), 20 - dropped);
+            Ok(connection.sent)
+        }
+
+        let first_run = answered(42).await?;
+        assert!(!first_run.is_empty() && first_run.len() < 20);
+        assert_eq!(answered(42).await?, first_run, "should be reproducible");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_release_port_once_stopped() -> Result<(), Box<dyn Error>> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .port();
+        let server = MockServer::default().port(port).start().await?;
+        assert!(server.is_listening());
+
+        server.stop();
+        server.stopped().await;
+        assert!(!server.is_listening());
+        let server = MockServer::default().port(port).start().await?;
+        assert_eq!(server.port(), port);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_messages_having_a_path() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .when_has_path("/token", json!({"auth": true}))
+            .on_exhausted(ExhaustBehavior::Echo);
+        let state = State::default();
+        let requests = [
+            json!({"token": "abc"}),
+            json!({"token": null}),
+            json!({"id": 1}),
+        ];
+        let mut connection = MockConnection::new(
+            requests
+                .iter()
+                .map(|request| Message::Text(request.to_string())),
+        );
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        let authenticated = Message::Text(json!({"auth": true}).to_string());
+        assert_eq!(
+            connection.sent,
+            vec![
+                authenticated.clone(),
+                authenticated,
+                Message::Text(requests[2].to_string()),
+            ]
+        );
+        let rules: Vec<_> = state
+            .match_log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.rule.clone())
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                MatchedRule::Matcher(0),
+                MatchedRule::Matcher(0),
+                MatchedRule::Exhausted
+            ]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_relay_messages_between_clients() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().relay(true).start().await?;
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let (stream, _) =
+                async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+            clients.push(stream);
+        }
+        eventually(|| server.connected_clients() == 3).await;
+
+        clients[0].send(Message::Text("offer".into())).await?;
+        for client in &mut clients[1..] {
+            assert_eq!(client.next().await.unwrap()?, Message::Text("offer".into()));
+        }
+        clients[2].close(None).await?;
+        eventually(|| server.connected_clients() == 2).await;
+        clients[1].send(Message::Binary(vec![1, 2])).await?;
+        assert_eq!(
+            clients[0].next().await.unwrap()?,
+            Message::Binary(vec![1, 2])
+        );
+
+        // the sender only receives its own messages when enabled
+        let received =
+            async_std::future::timeout(Duration::from_millis(50), clients[1].next()).await;
+        assert!(received.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_broadcast_to_connected_clients() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        assert_eq!(server.broadcast(MockResponse::Text("nobody".into())), 0);
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let (stream, _) =
+                async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+            clients.push(stream);
+        }
+        eventually(|| server.connected_clients() == 2).await;
+
+        assert_eq!(server.broadcast(MockResponse::Text("hello".into())), 2);
+        for client in &mut clients {
+            assert_eq!(client.next().await.unwrap()?, Message::Text("hello".into()));
+        }
+        assert_eq!(server.failed_deliveries(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn should_skip_ended_connections_when_broadcasting() {
+        let state = State::default();
+        let _active = state.connect(0);
+        drop(state.connect(1));
+
+        assert_eq!(
+            state.broadcast(Pushed::Response(MockResponse::Text("hello".into())), None),
+            1
+        );
+        assert_eq!(state.failed_deliveries.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn should_send_preamble_before_handshake() -> Result<(), Box<dyn Error>> {
+        use async_std::io::ReadExt;
+
+        let preamble = b"HTTP/1.0 200 OK\r\n\r\n";
+        let server = MockServer::default()
+            .preamble(preamble.to_vec())
+            .start()
+            .await?;
+        let mut stream = async_std::net::TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .await?;
+        let mut response = Vec::new();
+        let mut buffer = [0; 512];
+        while response
+            .windows(4)
+            .filter(|window| window == b"\r\n\r\n")
+            .count()
+            < 2
+        {
+            let len = stream.read(&mut buffer).await?;
+            assert_ne!(len, 0, "connection closed before the handshake response");
+            response.extend_from_slice(&buffer[..len]);
+        }
+        assert!(response.starts_with(preamble));
+        assert!(response[preamble.len()..].starts_with(b"HTTP/1.1 101 Switching Protocols"));
+
+        // compliant clients reject it
+        assert!(
+            async_tungstenite::async_std::connect_async(server.endpoint())
+                .await
+                .is_err()
+        );
+        // counted once written, which may be after the client gave up
+        eventually(|| server.sent_preambles() == 2).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_mask_server_frames() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, error::ProtocolError};
+
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .mask_server_frames(true)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("Some request".into())).await?;
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(tungstenite::Error::Protocol(
+                ProtocolError::MaskedFrameFromServer
+            )))
+        ));
+        assert_eq!(server.masked_frames(), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_by_sequence_number() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .respond_by_seq(
+                "/seq",
+                vec![
+                    (1, json!({"result": "first"})),
+                    (2, json!({"result": "second"})),
+                ]
+                .into_iter()
+                .collect(),
+            )
+            .on_exhausted(ExhaustBehavior::Echo);
+        let state = State::default();
+        let requests = [
+            json!({"seq": 2}),
+            json!({"seq": 1}),
+            json!({"seq": 3}),
+            json!({"seq": "1"}),
+        ];
+        let mut connection = MockConnection::new(
+            requests
+                .iter()
+                .map(|request| Message::Text(request.to_string())),
+        );
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        let expected = vec![
+            json!({"seq": 2, "result": "second"}).to_string(),
+            json!({"seq": 1, "result": "first"}).to_string(),
+            requests[2].to_string(),
+            requests[3].to_string(),
+        ];
+        assert_eq!(
+            connection.sent,
+            expected.into_iter().map(Message::Text).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_ramp_latency() {
+        let (start, end, over) = (
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+            Duration::from_secs(10),
+        );
+        assert_eq!(ramp(start, end, over, Duration::ZERO), start);
+        assert_eq!(
+            ramp(start, end, over, Duration::from_secs(5)),
+            Duration::from_millis(200)
+        );
+        assert_eq!(ramp(start, end, over, Duration::from_secs(20)), end);
+        assert_eq!(
+            ramp(end, start, over, Duration::from_secs(5)),
+            Duration::from_millis(200)
+        );
+    }
+
+    #[async_std::test]
+    async fn should_delay_responses_along_latency_ramp() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .latency_ramp(
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_secs(2),
+            );
+        let started = Instant::now();
+        let state = State {
+            started_at: started.checked_sub(Duration::from_secs(1)),
+            ..State::default()
+        };
+        let mut connection = MockConnection::new(vec![Message::Text("Some request".into())]);
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        let delays = state.response_delays.lock().unwrap().clone();
+        assert_eq!(delays.len(), 1);
+        // the delay grows with the time the response took to be sent
+        let elapsed = started.elapsed();
+        let latest = ramp(
+            Duration::ZERO,
+            Duration::from_millis(100),
+            Duration::from_secs(2),
+            Duration::from_secs(1) + elapsed,
+        );
+        assert!(delays[0] >= Duration::from_millis(50) && delays[0] <= latest);
+        assert!(elapsed >= delays[0]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_corrupt_accept_key() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, error::ProtocolError};
+
+        let server = MockServer::default()
+            .corrupt_accept_key(true)
+            .start()
+            .await?;
+
+        assert!(matches!(
+            async_tungstenite::async_std::connect_async(server.endpoint()).await,
+            Err(tungstenite::Error::Protocol(
+                ProtocolError::SecWebSocketAcceptKeyMismatch
+            ))
+        ));
+        assert_eq!(server.corrupted_accept_keys(), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_record_recent_transcript() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![
+                MockResponse::from(json!({"id": 1})),
+                MockResponse::compressed(json!({"id": 2})),
+            ])
+            .record_transcript(3)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for request in ["first", "second"] {
+            stream.send(Message::Text(request.into())).await?;
+            stream.next().await.unwrap().ok();
+        }
+
+        let entries: Vec<_> = server
+            .transcript()
+            .into_iter()
+            .map(|entry| (entry.direction, entry.message))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (Direction::Sent, Message::Text(json!({"id": 1}).to_string())),
+                (Direction::Received, Message::Text("second".into())),
+                (Direction::Sent, Message::Text(json!({"id": 2}).to_string())),
+            ]
+        );
+        let recent: Vec<_> = server
+            .recent_transcript(1)
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect();
+        assert_eq!(recent, vec![Message::Text(json!({"id": 2}).to_string())]);
+        assert_eq!(server.recent_transcript(10).len(), 3);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_limit_recorded_messages() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().record_limit(2).start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for request in ["first", "second", "third"] {
+            stream.send(Message::Text(request.into())).await?;
+        }
+        let third = server
+            .wait_for(
+                |message| *message == Message::Text("third".into()),
+                Duration::from_secs(1),
+            )
+            .await?;
+        assert_eq!(third.message, Message::Text("third".into()));
+
+        let received: Vec<_> = server
+            .received_messages()
+            .into_iter()
+            .map(|recorded| recorded.message)
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                Message::Text("second".into()),
+                Message::Text("third".into())
+            ]
+        );
+        assert_eq!(server.evicted_messages(), 1);
+
+        stream.send(Message::Text("fourth".into())).await?;
+        server
+            .wait_for(
+                |message| *message == Message::Text("fourth".into()),
+                Duration::from_secs(1),
+            )
+            .await?;
+        assert_eq!(server.received_messages().len(), 2);
+        assert_eq!(server.evicted_messages(), 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_alternate_responses() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .alternate(vec![json!("ping"), json!("pong")])
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for expected in ["ping", "pong", "ping", "pong", "ping"] {
+            stream.send(Message::Text("{}".into())).await?;
+            let response = stream.next().await.unwrap()?;
+            assert_eq!(response, Message::Text(json!(expected).to_string()));
+        }
+        let rules: Vec<_> = server
+            .match_log()
+            .into_iter()
+            .map(|entry| entry.rule)
+            .collect();
+        assert_eq!(
+            rules[..2],
+            [MatchedRule::Alternate(0), MatchedRule::Alternate(1)]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_empty_alternate() {
+        let error = MockServer::default()
+            .alternate(vec![])
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "alternate",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    async fn should_transform_responses() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![
+                MockResponse::from(json!({"id": 1})),
+                MockResponse::Text("plain".into()),
+            ])
+            .always_respond(json!({"id": 2}))
+            .transform_response(|mut value| {
+                value["server"] = json!("surimi");
+                value
+            })
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            stream.send(Message::Text("{}".into())).await?;
+            responses.push(stream.next().await.unwrap()?);
+        }
+        assert_eq!(
+            responses,
+            vec![
+                Message::Text(json!({"id": 1, "server": "surimi"}).to_string()),
+                Message::Text("plain".into()),
+                Message::Text(json!({"id": 2, "server": "surimi"}).to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_inspect_requests() -> Result<(), Box<dyn Error>> {
+        let inspected = Arc::new(Mutex::new(Vec::new()));
+        let server = MockServer::default()
+            .inspect_request({
+                let inspected = inspected.clone();
+                move |message| inspected.lock().unwrap().push(message.clone())
+            })
+            .on_exhausted(ExhaustBehavior::Echo)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Text("hello".into())).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Text("hello".into()));
+        stream.send(Message::Ping(vec![1])).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Pong(vec![1]));
+
+        assert_eq!(
+            *inspected.lock().unwrap(),
+            vec![Message::Text("hello".into()), Message::Ping(vec![1])]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_write_raw_bytes_responses() -> Result<(), Box<dyn Error>> {
+        use async_std::io::ReadExt;
+
+        // a text frame declaring 5 bytes of payload but carrying 2
+        let malformed = vec![0x81, 0x05, b'h', b'i'];
+        let server = MockServer::default()
+            .raw_bytes_response(malformed.clone())
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("{}".into())).await?;
+        let tcp = stream.get_mut();
+
+        let mut received = vec![0; malformed.len()];
+        tcp.read_exact(&mut received).await?;
+        assert_eq!(received, malformed);
+        eventually(|| server.raw_responses().len() == 1).await;
+        assert_eq!(
+            server.raw_responses()[0].message,
+            Message::Binary(malformed)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_resume_sessions() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses((1..=3).map(|id| json!({ "id": id })).collect())
+            .resumable(true)
+            .start()
+            .await?;
+        let endpoint = server.endpoint();
+
+        // the second connection resumes the session, the third starts fresh
+        for (session, expected) in [("abc", 1), ("abc", 2), ("new", 1)] {
+            let (mut stream, _) = async_tungstenite::async_std::connect_async(format!(
+                "{}/?session={}",
+                endpoint, session
+            ))
+            .await?;
+            stream.send(Message::Text("{}".into())).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({ "id": expected }).to_string())
+            );
+            assert_eq!(server.active_sessions(), vec![session.to_string()]);
+            stream.close(None).await?;
+            eventually(|| server.active_sessions().is_empty()).await;
+        }
+        assert_eq!(
+            server.sessions(),
+            HashMap::from([("abc".to_string(), 2), ("new".to_string(), 1)])
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_large_headers() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+        let server = MockServer::default().max_header_size(512).start().await?;
+
+        let mut request = server.endpoint().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Cookie", "a".repeat(512).parse()?);
+        match async_tungstenite::async_std::connect_async(request).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 431),
+            other => panic!("expected a 431 response, got {:?}", other),
+        }
+        // too many headers to parse, even though they are small
+        let mut request = server.endpoint().into_client_request()?;
+        for index in 0..130 {
+            request.headers_mut().insert(
+                tungstenite::http::header::HeaderName::from_bytes(
+                    format!("x-header-{}", index).as_bytes(),
+                )?,
+                "a".parse()?,
+            );
+        }
+        match async_tungstenite::async_std::connect_async(request).await {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 431),
+            other => panic!("expected a 431 response, got {:?}", other),
+        }
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+
+        assert_eq!(server.oversized_headers(), 2);
+        assert_eq!(server.handshake_statuses(), vec![431, 431, 101]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_close_on_connect() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .close_on_connect(CloseCode::Away, "shutting down")
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        let _ = stream.send(Message::Text("{}".into())).await;
+
+        let frame = CloseFrame {
+            code: CloseCode::Away,
+            reason: "shutting down".into(),
+        };
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Close(Some(frame.clone()))
+        );
+        while stream.next().await.is_some() {}
+        assert_eq!(server.connect_close_frame(0), Some(frame));
+        assert!(server.received_messages().is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_handle_opcodes() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .opcode_handlers(HashMap::from([
+                (
+                    Opcode::Binary,
+                    OpcodeBehavior::Respond(MockResponse::Binary(vec![0xff])),
+                ),
+                (
+                    Opcode::Ping,
+                    OpcodeBehavior::Respond(MockResponse::Text("no pong".into())),
+                ),
+                (Opcode::Pong, OpcodeBehavior::Ignore),
+                (Opcode::Text, OpcodeBehavior::Default),
+            ]))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        stream.send(Message::Binary(vec![1])).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Binary(vec![0xff]));
+        stream.send(Message::Ping(vec![1])).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text("no pong".into())
+        );
+        stream.send(Message::Pong(vec![1])).await?;
+        stream.send(Message::Text("{}".into())).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Text(json!({"id": 1}).to_string())
+        );
+
+        assert!(server.received_pongs().is_empty());
+        assert_eq!(server.received_pings()[0].ponged_at, None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_reject_responses_to_close_frames() {
+        let error = MockServer::default()
+            .opcode_handlers(HashMap::from([(
+                Opcode::Close,
+                OpcodeBehavior::Respond(json!({}).into()),
+            )]))
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<ConfigError>(),
+            Some(ConfigError::InvalidValue {
+                option: "opcode_handlers",
+                ..
+            })
+        ));
+    }
+
+    #[async_std::test]
+    async fn should_drain_received_messages() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .on_exhausted(ExhaustBehavior::Echo)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for phase in ["first", "second"] {
+            for _ in 0..2 {
+                stream.send(Message::Text(phase.into())).await?;
+                stream.next().await.unwrap()?;
+            }
+            let drained: Vec<_> = server
+                .drain_received()
+                .into_iter()
+                .map(|recorded| recorded.message)
+                .collect();
+            assert_eq!(drained, vec![Message::Text(phase.into()); 2]);
+            assert!(server.received_messages().is_empty());
+        }
+
+        stream.send(Message::Text("third".into())).await?;
+        let third = server
+            .wait_for(|message| message.is_text(), Duration::from_secs(1))
+            .await?;
+        assert_eq!(third.message, Message::Text("third".into()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_serve_once() -> Result<(), Box<dyn Error>> {
+        let bound = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .bind()
+            .await?;
+        let endpoint = format!("ws://{}", bound.local_addr());
+        let client = async {
+            let (mut stream, _) = async_tungstenite::async_std::connect_async(endpoint).await?;
+            stream.send(Message::Text("hello".into())).await?;
+            let response = stream.next().await.unwrap()?;
+            stream.close(None).await?;
+            while stream.next().await.is_some() {}
+            Ok::<_, Box<dyn Error>>(response)
+        };
+
+        let (received, response) = future::join(bound.serve_once(), client).await;
+        assert_eq!(response?, Message::Text(json!({"id": 1}).to_string()));
+        assert_eq!(
+            received?,
+            vec![RecordedMessage {
+                connection: 0,
+                message: Message::Text("hello".into()),
+            }]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_handle_unsupported_frames() -> Result<(), Box<dyn Error>> {
+        use async_std::io::WriteExt;
+
+        // masked frames of the reserved data opcode 3 and control opcode 11
+        let frames = [
+            vec![0x83, 0x82, 1, 2, 3, 4, b'h', b'i'],
+            vec![0x8b, 0x80, 1, 2, 3, 4],
+        ];
+        let server = MockServer::default()
+            .on_unsupported(UnsupportedBehavior::Respond(
+                json!({"error": "unsupported"}),
+            ))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        for frame in &frames {
+            stream.get_mut().write_all(frame).await?;
+            assert_eq!(
+                stream.next().await.unwrap()?,
+                Message::Text(json!({"error": "unsupported"}).to_string())
+            );
+        }
+        assert_eq!(
+            server.unsupported_frames(),
+            vec![
+                UnsupportedFrame {
+                    connection: 0,
+                    opcode: 3
+                },
+                UnsupportedFrame {
+                    connection: 0,
+                    opcode: 11
+                },
+            ]
+        );
+
+        let server = MockServer::default()
+            .on_unsupported(UnsupportedBehavior::Close(CloseCode::Protocol))
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.get_mut().write_all(&frames[0]).await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Protocol,
+                reason: "".into(),
+            }))
+        );
+
+        // by default, tungstenite fails the connection
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.get_mut().write_all(&frames[1]).await?;
+        while let Some(Ok(_)) = stream.next().await {}
+        eventually(|| server.unsupported_frames().len() == 1).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_messages_with_matchers() -> Result<(), Box<dyn Error>> {
+        use connection::MockConnection;
+
+        let server = MockServer::default()
+            .add_matcher(Box::new(|message: &Message| match message {
+                Message::Binary(data) => Some(MockResponse::Binary(data.repeat(2))),
+                message => {
+                    (message.to_text().ok()? == "PING").then(|| MockResponse::Text("PONG".into()))
+                }
+            }))
+            .add_matcher(Box::new(HasPath::new("/token", json!({"auth": true}))))
+            .add_matcher(Box::new(HasPath::new("/token", json!({"auth": false}))))
+            .on_exhausted(ExhaustBehavior::Echo);
+        let state = State::default();
+        let token = json!({"token": "abc"}).to_string();
+        let mut connection = MockConnection::new(vec![
+            Message::Text("PING".into()),
+            Message::Text(token.clone()),
+            Message::Binary(vec![1]),
+            Message::Text("PONG".into()),
+        ]);
+
+        server
+            .serve_connection(
+                &mut connection,
+                0,
+                &state,
+                state.connect(0),
+                server.responses.clone().into(),
+                None,
+            )
+            .await?;
+        assert_eq!(
+            connection.sent,
+            vec![
+                Message::Text("PONG".into()),
+                Message::Text(json!({"auth": true}).to_string()),
+                Message::Binary(vec![1, 1]),
+                Message::Text("PONG".into()),
+            ]
+        );
+        let rules: Vec<_> = state
+            .match_log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.rule.clone())
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                MatchedRule::Matcher(0),
+                MatchedRule::Matcher(1),
+                MatchedRule::Exhausted
+            ]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_hang_handshake() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().hang_handshake(true).start().await?;
+        let connect = async_tungstenite::async_std::connect_async(server.endpoint());
+        assert!(
+            async_std::future::timeout(Duration::from_millis(200), connect)
+                .await
+                .is_err()
+        );
+        // the next connection is accepted while the first one hangs
+        let second = async_tungstenite::async_std::connect_async(server.endpoint());
+        assert!(
+            async_std::future::timeout(Duration::from_millis(200), second)
+                .await
+                .is_err()
+        );
+        assert_eq!(server.hung_handshakes(), 2);
+        eventually(|| server.in_flight_connections() == 0).await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_assert_messages_order() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        for request in ["auth", "subscribe", "auth"] {
+            stream.send(Message::Text(request.into())).await?;
+            stream.next().await.unwrap()?;
+        }
+
+        let is =
+            |text: &'static str| move |message: &Message| *message == Message::Text(text.into());
+        server.assert_order(is("auth"), is("subscribe"));
+        let reversed =
+            std::panic::catch_unwind(|| server.assert_order(is("subscribe"), is("auth")));
+        let message = reversed.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(
+            *message,
+            "expected subscribe (message #1) to be received before auth (message #0)"
+        );
+        let missing = std::panic::catch_unwind(|| server.assert_order(is("auth"), is("close")));
+        let message = missing.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(
+            *message,
+            "expected ordered messages but the `after` one was not received"
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_require_basic_auth() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+        let server = MockServer::default()
+            .basic_auth("user".into(), "pass".into())
+            .start()
+            .await?;
+        let connect = |authorization: Option<&str>| {
+            let mut request = server.endpoint().into_client_request().unwrap();
+            if let Some(authorization) = authorization {
+                request
+                    .headers_mut()
+                    .insert("Authorization", authorization.parse().unwrap());
+            }
+            async_tungstenite::async_std::connect_async(request)
+        };
+        // base64 of "user:pass", then "user:word"
+        assert!(connect(Some("Basic dXNlcjpwYXNz")).await.is_ok());
+        match connect(Some("Basic dXNlcjp3b3Jk")).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 401);
+                assert_eq!(
+                    response.headers()["WWW-Authenticate"],
+                    "Basic realm=\"surimi\""
+                );
+            }
+            other => panic!("expected a 401, got {:?}", other.map(|_| ())),
+        }
+        assert!(connect(Some("Basic %%%")).await.is_err());
+        assert!(connect(None).await.is_err());
+        assert_eq!(
+            server.basic_credentials(),
+            vec![
+                Some(("user".into(), "pass".into())),
+                Some(("user".into(), "word".into())),
+                None,
+                None
+            ]
+        );
+        assert_eq!(server.handshake_statuses(), vec![101, 401, 401, 401]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_echo_close_frames() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default().start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream
+            .close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: "bye".into(),
+            }))
+            .await?;
+        assert_eq!(
+            stream.next().await.unwrap()?,
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            }))
+        );
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        assert_eq!(stream.next().await.unwrap()?, Message::Close(None));
+
+        let server = MockServer::default().close_echo(false).start().await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.close(None).await?;
+        assert!(!matches!(stream.next().await, Some(Ok(Message::Close(_)))));
+        eventually(|| server.disconnections().len() == 1).await;
+        assert_eq!(server.disconnections()[0].reason, DisconnectReason::Closed);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_measure_latencies() -> Result<(), Box<dyn Error>> {
+        let delay = Duration::from_millis(50);
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .latency_ramp(delay, delay, Duration::from_secs(1))
+            .on_exhausted(ExhaustBehavior::Silent)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        let sent_at = Instant::now();
+        stream.send(Message::Text("first".into())).await?;
+        stream.next().await.unwrap()?;
+        let turnaround = sent_at.elapsed();
+        // a push following an unanswered message is not a response
+        stream.send(Message::Text("second".into())).await?;
+        eventually(|| server.received_messages().len() == 2).await;
+        server.send(json!({"pushed": true}).into())?;
+        stream.next().await.unwrap()?;
+        stream.close(None).await?;
+        while stream.next().await.is_some() {}
+
+        let metrics = server.metrics();
+        assert_eq!(metrics.handshakes.count, 1);
+        assert!(metrics.handshakes.max > Duration::ZERO);
+        assert_eq!(metrics.responses.count, 1);
+        let latency = metrics.responses.mean;
+        assert!(latency >= delay && latency <= turnaround);
+        assert_eq!(
+            (metrics.responses.min, metrics.responses.max),
+            (latency, latency)
+        );
+
+        eventually(|| !server.connection_stats().is_empty()).await;
+        let stats = &server.connection_stats()[0];
+        assert_eq!(stats.response_latency, metrics.responses);
+        assert_eq!(stats.handshake_duration, metrics.handshakes.max);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_capture_values() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"token": "abc"}), json!({"ok": true})])
+            .capture("/token", "token")
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        for request in [json!({"login": "user"}), json!({"auth": "Bearer abc"})] {
+            stream.send(Message::Text(request.to_string())).await?;
+            stream.next().await.unwrap()?;
+        }
+        assert_eq!(
+            server.captures(0),
+            HashMap::from([("token".to_string(), json!("abc"))])
+        );
+        server.assert_contains("token");
+        assert!(std::panic::catch_unwind(|| server.assert_contains("session")).is_err());
+
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream
+            .send(Message::Text(json!({"login": "user"}).to_string()))
+            .await?;
+        stream.next().await.unwrap()?;
+        let missing = std::panic::catch_unwind(|| server.assert_contains("token"));
+        let message = missing.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!(
+            *message,
+            "no message received on connection 1 after capturing token contained \"abc\""
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_answer_http_fallback_in_chunks() -> Result<(), Box<dyn Error>> {
+        use async_std::io::{ReadExt, WriteExt};
+        use async_std::net::TcpStream;
+
+        let server = MockServer::default()
+            .http_route("/health".into(), 200, "up".into(), "text/plain".into())
+            .http_fallback_chunked(vec!["{\"id\":1}".into(), "".into(), "{\"id\":2}\n".into()])
+            .start()
+            .await?;
+        let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(b"POST /poll HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n8\r\n{\"id\":1}\r\n9\r\n{\"id\":2}\n\r\n0\r\n\r\n"
+        );
+
+        let mut stream = TcpStream::connect((server.host(), server.port())).await?;
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.ends_with("\r\n\r\nup"));
+        assert!(
+            async_tungstenite::async_std::connect_async(server.endpoint())
+                .await
+                .is_ok()
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_set_socket_buffer_sizes() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .recv_buffer_size(64 * 1024)
+            .send_buffer_size(32 * 1024)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        stream.send(Message::Text("Some request".into())).await?;
+        stream.next().await.unwrap()?;
+
+        let sizes = server.buffer_sizes();
+        assert_eq!(sizes.len(), 1);
+        let (recv, send) = sizes[0];
+        // the OS may round or double them, but not ignore them
+        assert!(recv >= 32 * 1024 && send >= 16 * 1024);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_accept_handshakes_with_many_headers() -> Result<(), Box<dyn Error>> {
+        use async_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+        let server = MockServer::default().start().await?;
+        let mut request = server.endpoint().into_client_request()?;
+        // more than the first 64 tungstenite accepts too
+        for index in 0..100 {
+            request.headers_mut().insert(
+                tungstenite::http::header::HeaderName::from_bytes(
+                    format!("x-header-{}", index).as_bytes(),
+                )?,
+                "a".parse()?,
+            );
+        }
+        let (mut stream, _) = async_tungstenite::async_std::connect_async(request).await?;
+        stream.close(None).await?;
+        assert_eq!(server.handshake_statuses(), vec![101]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_relay_messages_unchanged() -> Result<(), Box<dyn Error>> {
+        std::env::set_var("SURIMI_TEST_RELAYED", "interpolated");
+        let server = MockServer::default()
+            .relay(true)
+            .interpolate_env(true)
+            .start()
+            .await?;
+        let (mut sender, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        let (mut receiver, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+        eventually(|| server.connected_clients() == 2).await;
+
+        sender
+            .send(Message::Text("${SURIMI_TEST_RELAYED} {now}".into()))
+            .await?;
+        assert_eq!(
+            receiver.next().await.unwrap()?,
+            Message::Text("${SURIMI_TEST_RELAYED} {now}".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn should_replace_responses_with_raw_bytes_response() {
+        let server = MockServer::default()
+            .responses(vec![json!({"id": 1})])
+            .raw_bytes_response(vec![0x81, 0x00]);
+        assert_eq!(
+            server.configured_responses(),
+            &[MockResponse::Raw(vec![0x81, 0x00])]
+        );
+
+        let server = MockServer::default()
+            .raw_bytes_response(vec![0x81, 0x00])
+            .responses(vec![json!({"id": 1})]);
+        assert_eq!(
+            server.configured_responses(),
+            &[MockResponse::Json(json!({"id": 1}))]
+        );
+    }
+
+    #[async_std::test]
+    async fn should_send_partial_out_of_order_batches() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .responses(vec![json!({"n": 1}), json!({"n": 2}), json!({"n": 3})])
+            .out_of_order(true)
+            .out_of_order_batch(2, 0)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for id in 1..=3 {
+            stream
+                .send(Message::Text(json!({ "id": id }).to_string()))
+                .await?;
+        }
+        let mut received = vec![];
+        for _ in 0..3 {
+            let message = stream.next().await.unwrap()?;
+            received.push(serde_json::from_str::<Value>(&message.into_text()?)?);
+        }
+        received.sort_by_key(|response| response["id"].as_u64());
+        assert_eq!(
+            received,
+            vec![
+                json!({"n": 1, "id": 1}),
+                json!({"n": 2, "id": 2}),
+                json!({"n": 3, "id": 3}),
+            ]
+        );
+
+        stream.close(None).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn should_alternate_answered_messages_only() -> Result<(), Box<dyn Error>> {
+        let server = MockServer::default()
+            .alternate(vec![json!("ping"), json!("pong")])
+            .respond_every(2)
+            .start()
+            .await?;
+        let (mut stream, _) =
+            async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+
+        for expected in ["ping", "pong", "ping"] {
+            stream.send(Message::Text("{}".into())).await?;
+            stream.send(Message::Text("{}".into())).await?;
+            let response = stream.next().await.unwrap()?;
+            assert_eq!(response, Message::Text(json!(expected).to_string()));
+        }
         Ok(())
     }
 }
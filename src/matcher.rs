@@ -0,0 +1,86 @@
+//! Matchers routing received messages to responses, an extension point to
+//! answer messages with custom logic, registered with
+//! `MockServer::add_matcher`.
+//!
+//! A `Matcher` is given a received text or binary message and returns the
+//! response to answer it with, or `None` to let the next rule answer it.
+//! Matchers are tried in the order they were registered, and the first
+//! response returned answers the message. For text messages, matchers come
+//! after the subscription controls, `respond_every` and `scenario` rules,
+//! and before the `respond_by_seq` responses, `when_query` rules,
+//! responders and the responses queue. Binary messages no matcher answers
+//! are answered by `always_respond`, if set.
+//! Closures taking a `&Message` and returning an `Option<MockResponse>`
+//! are matchers, and `HasPath` is the matcher registered by
+//! `MockServer::when_has_path`.
+//!
+//! # Examples
+//! ```
+//! use async_tungstenite::tungstenite::Message;
+//! use surimi::{HasPath, MockResponse, MockServer};
+//! use serde_json::json;
+//!
+//! # #[async_std::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let server = MockServer::default()
+//!     .add_matcher(Box::new(|message: &Message| {
+//!         let text = message.to_text().ok()?;
+//!         text.starts_with("PING").then(|| MockResponse::Text("PONG".into()))
+//!     }))
+//!     .add_matcher(Box::new(HasPath::new("/token", json!({"auth": "ok"}))))
+//!     .start()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::response::MockResponse;
+use async_tungstenite::tungstenite::protocol::Message;
+use serde_json::Value;
+
+/// Matcher answers the received messages it matches.
+pub trait Matcher {
+    /// Response to answer the text or binary `message` with, or `None` if
+    /// it does not match. It is called from the task serving the
+    /// connection, so it should not block.
+    fn matches(&self, message: &Message) -> Option<MockResponse>;
+}
+
+impl<F> Matcher for F
+where
+    F: Fn(&Message) -> Option<MockResponse>,
+{
+    fn matches(&self, message: &Message) -> Option<MockResponse> {
+        self(message)
+    }
+}
+
+/// HasPath matches JSON text messages holding a value at a JSON pointer
+/// (RFC 6901), whatever the value, as registered by
+/// `MockServer::when_has_path`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HasPath {
+    pointer: String,
+    response: Value,
+}
+
+impl HasPath {
+    /// Match the messages holding a value at `pointer` with `response`.
+    pub fn new(pointer: &str, response: Value) -> Self {
+        Self {
+            pointer: pointer.into(),
+            response,
+        }
+    }
+}
+
+impl Matcher for HasPath {
+    fn matches(&self, message: &Message) -> Option<MockResponse> {
+        let text = match message {
+            Message::Text(text) => text,
+            _ => return None,
+        };
+        let request: Value = serde_json::from_str(text).ok()?;
+        request.pointer(&self.pointer)?;
+        Some(self.response.clone().into())
+    }
+}
@@ -0,0 +1,215 @@
+use crate::env::{self, MissingEnv, MissingVariable};
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use async_tungstenite::tungstenite::protocol::Message;
+use serde_json::Value;
+use std::convert::Infallible;
+use std::time::SystemTime;
+
+/// Placeholder substituted with the current time in responses.
+const NOW_PLACEHOLDER: &str = "{now}";
+
+/// MockResponse is a message the server can send to a client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockResponse {
+    /// A JSON value, sent serialized in a text frame.
+    Json(Value),
+    /// A text frame.
+    Text(String),
+    /// A binary frame.
+    Binary(Vec<u8>),
+    /// The response, sent in a frame compressed with permessage-deflate
+    /// (RSV1 bit set), while other frames are sent uncompressed.
+    /// The client must have negotiated the extension for this frame to be
+    /// valid, e.g. with a `Sec-WebSocket-Extensions: permessage-deflate`
+    /// response header.
+    Compressed(Box<MockResponse>),
+    /// Bytes written as is on the connection instead of a frame, see
+    /// `MockServer::raw_bytes_response`.
+    Raw(Vec<u8>),
+}
+
+impl MockResponse {
+    /// Send `response` in a frame compressed with permessage-deflate.
+    pub fn compressed(response: impl Into<MockResponse>) -> Self {
+        Self::Compressed(Box::new(response.into()))
+    }
+
+    /// Message carrying the response, uncompressed.
+    pub(crate) fn to_message(&self) -> Message {
+        match self {
+            Self::Json(value) => Message::Text(value.to_string()),
+            Self::Text(text) => Message::Text(text.clone()),
+            Self::Binary(data) => Message::Binary(data.clone()),
+            Self::Compressed(response) => response.to_message(),
+            Self::Raw(bytes) => Message::Binary(bytes.clone()),
+        }
+    }
+
+    /// Response with the `{now}` placeholders of its text, or of the strings
+    /// of its JSON value, replaced by `now` formatted as RFC 3339.
+    pub(crate) fn stamp(&self, now: SystemTime) -> Self {
+        let now = humantime::format_rfc3339_millis(now).to_string();
+        let stamped =
+            self.map_text(&|text| Ok::<_, Infallible>(text.replace(NOW_PLACEHOLDER, &now)));
+        match stamped {
+            Ok(response) => response,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Response with the `${VAR}` tokens of its text, or of the strings of
+    /// its JSON value, replaced by the environment variables.
+    pub(crate) fn interpolate_env(&self, missing: MissingEnv) -> Result<Self, MissingVariable> {
+        self.map_text(&|text| env::interpolate(text, missing))
+    }
+
+    /// Serialize the JSON value of the response to text once, so it is not
+    /// serialized again each time it is sent. Responses with `{now}`
+    /// placeholders keep their value, so only the placeholders of its
+    /// strings are stamped.
+    pub(crate) fn serialize(&mut self) {
+        match self {
+            Self::Json(value) => {
+                let text = value.to_string();
+                if !text.contains(NOW_PLACEHOLDER) {
+                    *self = Self::Text(text);
+                }
+            }
+            Self::Compressed(response) => response.serialize(),
+            _ => {}
+        }
+    }
+
+    /// Response tagged with the request `id`, set at the JSON `pointer` of
+    /// its JSON value. The pointer must exist in the value, or be a single
+    /// field of a JSON object (e.g. `/id`) which is then added to it.
+    /// Other responses are returned unchanged.
+    pub(crate) fn with_id(mut self, pointer: &str, id: Value) -> Self {
+        if let Self::Json(value) = &mut self {
+            if let Some(target) = value.pointer_mut(pointer) {
+                *target = id;
+            } else if let (Some(field), Value::Object(fields)) = (pointer.strip_prefix('/'), value)
+            {
+                if !field.contains('/') {
+                    fields.insert(field.replace("~1", "/").replace("~0", "~"), id);
+                }
+            }
+        }
+        self
+    }
+
+    /// Response with its JSON value, if any, replaced by `f` applied to it.
+    /// Text and binary responses are returned unchanged.
+    pub(crate) fn map_json(self, f: &impl Fn(Value) -> Value) -> Self {
+        match self {
+            Self::Json(value) => Self::Json(f(value)),
+            Self::Compressed(response) => Self::Compressed(Box::new(response.map_json(f))),
+            other => other,
+        }
+    }
+
+    fn map_text<E>(&self, f: &impl Fn(&str) -> Result<String, E>) -> Result<Self, E> {
+        Ok(match self {
+            Self::Json(value) => Self::Json(map_strings(value, f)?),
+            Self::Text(text) => Self::Text(f(text)?),
+            Self::Binary(data) => Self::Binary(data.clone()),
+            Self::Compressed(response) => Self::Compressed(Box::new(response.map_text(f)?)),
+            Self::Raw(bytes) => Self::Raw(bytes.clone()),
+        })
+    }
+}
+
+fn map_strings<E>(value: &Value, f: &impl Fn(&str) -> Result<String, E>) -> Result<Value, E> {
+    Ok(match value {
+        Value::String(text) => Value::String(f(text)?),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| map_strings(value, f))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), map_strings(value, f)?)))
+                .collect::<Result<_, _>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+impl From<Value> for MockResponse {
+    fn from(value: Value) -> Self {
+        Self::Json(value)
+    }
+}
+
+/// What the server does with text messages received once its responses
+/// are exhausted, see `MockServer::on_exhausted`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExhaustBehavior {
+    /// Answer with a text frame. The default is `SendText("No more response")`.
+    SendText(String),
+    /// Close the connection with the given close code.
+    Close(CloseCode),
+    /// Do not answer.
+    Silent,
+    /// Answer with the received message.
+    Echo,
+}
+
+impl Default for ExhaustBehavior {
+    fn default() -> Self {
+        Self::SendText("No more response".into())
+    }
+}
+
+/// What the server does with frames of an opcode the WebSocket protocol
+/// reserves, see `MockServer::on_unsupported`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum UnsupportedBehavior {
+    /// End the connection with the protocol error. This is the default.
+    #[default]
+    Fail,
+    /// Drop the frame and keep serving the connection.
+    Ignore,
+    /// Close the connection with the given close code.
+    Close(CloseCode),
+    /// Answer with the value and keep serving the connection.
+    Respond(Value),
+}
+
+/// Opcode of a message received by the server, see
+/// `MockServer::opcode_handlers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Text,
+    Binary,
+    Ping,
+    Pong,
+    Close,
+}
+
+impl Opcode {
+    pub(crate) fn of(message: &Message) -> Self {
+        match message {
+            Message::Text(_) => Self::Text,
+            Message::Binary(_) => Self::Binary,
+            Message::Ping(_) => Self::Ping,
+            Message::Pong(_) => Self::Pong,
+            Message::Close(_) => Self::Close,
+        }
+    }
+}
+
+/// What the server does with the messages of an opcode, see
+/// `MockServer::opcode_handlers`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpcodeBehavior {
+    /// Handle them as usual.
+    Default,
+    /// Answer each of them with the response.
+    Respond(MockResponse),
+    /// Do not answer them.
+    Ignore,
+}
@@ -0,0 +1,178 @@
+use serde_json::Value;
+
+/// A rule of a scenario state: when a message equal to `matcher` is
+/// received, answer `response` and move to `next_state`.
+#[derive(Clone, Debug)]
+struct Rule {
+    name: Option<String>,
+    matcher: Value,
+    response: Option<Value>,
+    next_state: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+struct State {
+    name: String,
+    rules: Vec<Rule>,
+}
+
+/// Scenario describes a stateful server as a state machine: each connection
+/// starts in an initial state, and each state defines how to answer the
+/// received messages and which state to move to.
+///
+/// Rules are declared by chaining `state(name)`, then `on(matcher)` for
+/// each rule of the state, followed by `respond(response)` and/or
+/// `goto(next_state)`. A rule matches a text message parsed as JSON equal
+/// to its matcher. The first matching rule of the current state is used.
+/// Messages matching no rule of the current state are answered from the
+/// responses queue as usual.
+///
+/// # Examples
+/// ```
+/// use surimi::{run_conversation, MockServer, Scenario};
+/// use serde_json::json;
+///
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let scenario = Scenario::new("init")
+///         .state("init")
+///             .on(json!({"action": "auth"}))
+///             .respond(json!({"auth": "ok"}))
+///             .goto("authenticated")
+///         .state("authenticated")
+///             .on(json!({"action": "subscribe"}))
+///             .respond(json!({"subscribed": true}))
+///             .goto("streaming");
+///
+///     let server = MockServer::default()
+///         .responses(vec![json!({"error": "unexpected"})])
+///         .scenario(scenario)
+///         .start()
+///         .await?;
+///
+///     let (mut stream, _) = async_tungstenite::async_std::connect_async(server.endpoint()).await?;
+///     run_conversation(
+///         &mut stream,
+///         &[
+///             (json!({"action": "subscribe"}), json!({"error": "unexpected"})),
+///             (json!({"action": "auth"}), json!({"auth": "ok"})),
+///             (json!({"action": "subscribe"}), json!({"subscribed": true})),
+///         ],
+///     )
+///     .await?;
+///     assert_eq!(server.scenario_state(0), Some("streaming".into()));
+///
+///     stream.close(None).await?;
+/// #   Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    initial_state: String,
+    states: Vec<State>,
+}
+
+impl Scenario {
+    /// Create a scenario whose connections start in `initial_state`.
+    pub fn new(initial_state: impl Into<String>) -> Self {
+        Self {
+            initial_state: initial_state.into(),
+            states: vec![],
+        }
+    }
+
+    /// Start declaring the rules of the state `name`.
+    pub fn state(mut self, name: impl Into<String>) -> Self {
+        self.states.push(State {
+            name: name.into(),
+            rules: vec![],
+        });
+        self
+    }
+
+    /// Add a rule to the current state, matching messages equal to `matcher`.
+    ///
+    /// # Panics
+    /// Panics if no state was declared with `state` before.
+    pub fn on(mut self, matcher: Value) -> Self {
+        self.states
+            .last_mut()
+            .expect("Scenario::on must follow Scenario::state")
+            .rules
+            .push(Rule {
+                name: None,
+                matcher,
+                response: None,
+                next_state: None,
+            });
+        self
+    }
+
+    /// Answer the messages matching the current rule with `response`.
+    /// A rule without response transitions silently.
+    ///
+    /// # Panics
+    /// Panics if no rule was declared with `on` before.
+    pub fn respond(mut self, response: Value) -> Self {
+        self.last_rule("respond").response = Some(response);
+        self
+    }
+
+    /// Move to `state` when the current rule matches.
+    /// A rule without next state stays in the current one.
+    ///
+    /// # Panics
+    /// Panics if no rule was declared with `on` before.
+    pub fn goto(mut self, state: impl Into<String>) -> Self {
+        self.last_rule("goto").next_state = Some(state.into());
+        self
+    }
+
+    /// Name the current rule, as reported by `ServerHandle::match_log`.
+    ///
+    /// # Panics
+    /// Panics if no rule was declared with `on` before.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.last_rule("name").name = Some(name.into());
+        self
+    }
+
+    fn last_rule(&mut self, method: &str) -> &mut Rule {
+        self.states
+            .last_mut()
+            .and_then(|state| state.rules.last_mut())
+            .unwrap_or_else(|| panic!("Scenario::{} must follow Scenario::on", method))
+    }
+
+    pub(crate) fn initial_state(&self) -> &str {
+        &self.initial_state
+    }
+
+    /// Find the rule of `state` matching `message`.
+    pub(crate) fn transition<'a>(
+        &'a self,
+        state: &'a str,
+        message: &Value,
+    ) -> Option<Transition<'a>> {
+        self.states
+            .iter()
+            .filter(|candidate| candidate.name == state)
+            .flat_map(|candidate| candidate.rules.iter().enumerate())
+            .find(|(_, rule)| &rule.matcher == message)
+            .map(|(index, rule)| Transition {
+                rule: index,
+                name: rule.name.as_deref(),
+                response: rule.response.as_ref(),
+                next_state: rule.next_state.as_deref().unwrap_or(state),
+            })
+    }
+}
+
+/// The rule of a state matching a message.
+pub(crate) struct Transition<'a> {
+    /// Index of the rule in its state.
+    pub(crate) rule: usize,
+    pub(crate) name: Option<&'a str>,
+    pub(crate) response: Option<&'a Value>,
+    pub(crate) next_state: &'a str,
+}
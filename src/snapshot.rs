@@ -0,0 +1,105 @@
+use crate::handle::RecordedMessage;
+use async_tungstenite::tungstenite::protocol::Message;
+use serde_json::Value;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Environment variable which, when set to anything but `0`, makes
+/// `MockServer::snapshot` overwrite golden files instead of comparing them.
+const UPDATE_VAR: &str = "SURIMI_UPDATE_SNAPSHOTS";
+
+/// Compare the `received` messages with the golden file at `path`, writing
+/// it instead if it does not exist yet or if `UPDATE_VAR` is set.
+/// Returns an error describing the mismatch as a diff, or the I/O failure.
+pub(crate) fn check(path: &Path, received: &[RecordedMessage]) -> Result<(), String> {
+    let actual = format(received);
+    let update = std::env::var(UPDATE_VAR).is_ok_and(|value| value != "0");
+    if update || !path.exists() {
+        return std::fs::write(path, actual)
+            .map_err(|error| format!("cannot write snapshot {}: {}", path.display(), error));
+    }
+    let expected = std::fs::read_to_string(path)
+        .map_err(|error| format!("cannot read snapshot {}: {}", path.display(), error))?;
+    if expected == actual {
+        return Ok(());
+    }
+    Err(format!(
+        "received messages differ from snapshot {} (- expected, + received), \
+         set {}=1 to update it:\n{}",
+        path.display(),
+        UPDATE_VAR,
+        diff(&expected, &actual)
+    ))
+}
+
+/// Format the messages one per line, as `<connection> text <JSON string>`
+/// or `<connection> binary <hex bytes>`.
+fn format(received: &[RecordedMessage]) -> String {
+    let mut output = String::new();
+    for recorded in received {
+        let _ = match &recorded.message {
+            Message::Binary(data) => {
+                let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+                writeln!(output, "{} binary {}", recorded.connection, hex)
+            }
+            message => writeln!(
+                output,
+                "{} text {}",
+                recorded.connection,
+                Value::String(message.to_string())
+            ),
+        };
+    }
+    output
+}
+
+/// Line by line diff of two snapshots: lines are compared by position, and
+/// differing ones are listed as removed from `expected` then added.
+fn diff(expected: &str, actual: &str) -> String {
+    let (expected, actual): (Vec<_>, Vec<_>) =
+        (expected.lines().collect(), actual.lines().collect());
+    let mut output = String::new();
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(old), Some(new)) if old == new => {
+                let _ = writeln!(output, "  {}", old);
+            }
+            (old, new) => {
+                if let Some(old) = old {
+                    let _ = writeln!(output, "- {}", old);
+                }
+                if let Some(new) = new {
+                    let _ = writeln!(output, "+ {}", new);
+                }
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_and_diff_messages() {
+        let received = vec![
+            RecordedMessage {
+                connection: 0,
+                message: Message::Text("{\"id\":1}\n".into()),
+            },
+            RecordedMessage {
+                connection: 1,
+                message: Message::Binary(vec![0x0a, 0xff]),
+            },
+        ];
+        let snapshot = format(&received);
+        assert_eq!(snapshot, "0 text \"{\\\"id\\\":1}\\n\"\n1 binary 0aff\n");
+
+        let changed = snapshot.replace("0aff", "0a");
+        assert_eq!(
+            diff(&snapshot, &changed),
+            "  0 text \"{\\\"id\\\":1}\\n\"\n- 1 binary 0aff\n+ 1 binary 0a\n"
+        );
+    }
+}
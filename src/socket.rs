@@ -0,0 +1,83 @@
+use crate::MockServerOptions;
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::time::Duration;
+
+/// Bind a listener according to the server options.
+/// Like `TcpListener::bind`, every address `host` resolves to is tried
+/// until one of them can be bound.
+pub(crate) async fn bind(options: &MockServerOptions) -> io::Result<TcpListener> {
+    let backlog = match options.backlog {
+        Some(backlog) => backlog,
+        None => return TcpListener::bind((options.host.as_str(), options.port)).await,
+    };
+
+    let mut last_error = None;
+    for address in (options.host.as_str(), options.port)
+        .to_socket_addrs()
+        .await?
+    {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        match socket
+            .bind(&address.into())
+            .and_then(|_| socket.listen(backlog as i32))
+        {
+            Ok(_) => return Ok(TcpListener::from(std::net::TcpListener::from(socket))),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+/// Make closing `stream` reset the connection: with a zero linger timeout,
+/// the OS discards unsent data and sends a RST instead of a FIN.
+pub(crate) fn reset_on_close(stream: &TcpStream) -> io::Result<()> {
+    SockRef::from(stream).set_linger(Some(Duration::from_secs(0)))
+}
+
+/// Enable TCP keepalive on an accepted stream, probing the peer after it
+/// has been idle for `time`.
+pub(crate) fn set_keepalive(stream: &TcpStream, time: Duration) -> io::Result<()> {
+    SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))
+}
+
+/// Set the receive and send buffer sizes of an accepted stream, the ones
+/// given, and return the effective sizes of both, as reported by the OS.
+pub(crate) fn set_buffer_sizes(
+    stream: &TcpStream,
+    recv: Option<usize>,
+    send: Option<usize>,
+) -> io::Result<(usize, usize)> {
+    let socket = SockRef::from(stream);
+    if let Some(size) = recv {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = send {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok((socket.recv_buffer_size()?, socket.send_buffer_size()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn set_keepalive_on_stream() -> io::Result<()> {
+        let listener = TcpListener::bind("localhost:0").await?;
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+        assert!(!SockRef::from(&stream).keepalive()?);
+
+        set_keepalive(&stream, Duration::from_secs(30))?;
+        assert!(SockRef::from(&stream).keepalive()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+use crate::connection::Traffic;
+use crate::socket;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::{self, Read, Write};
+use async_std::net::TcpStream;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Transport is the byte stream a connection is served on.
+pub(crate) trait Transport: Read + Write + Unpin {
+    /// Make closing the transport reset the connection, if it supports it.
+    fn reset_on_close(&self) -> io::Result<()>;
+
+    /// Address of the peer, if the transport has one.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Transport for TcpStream {
+    fn reset_on_close(&self) -> io::Result<()> {
+        socket::reset_on_close(self)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+/// Replay wraps a stream whose first bytes were already read, and serve
+/// them again before reading from the stream itself.
+/// It lets the server inspect a request before handing the stream to
+/// tungstenite for the WebSocket handshake.
+/// The bytes are replayed in chunks, a read never returning bytes of two
+/// chunks, so that the request head can be replayed apart from the frames
+/// a client sent right after it.
+pub(crate) struct Replay<S> {
+    chunks: VecDeque<Vec<u8>>,
+    position: usize,
+    inner: S,
+    /// Scanner of the written frames, once pongs are filtered.
+    pongs: Option<PongFilter>,
+    /// Written bytes not yet forwarded to `inner`, when frames are scanned.
+    pending: Vec<u8>,
+    /// Scanner of the read frames, dropping the ones of reserved opcodes
+    /// and sending their opcode, once they are filtered.
+    reserved: Option<(FrameScanner, Sender<u8>)>,
+    /// Receiver of the opcodes of the dropped frames, until it is taken.
+    reserved_opcodes: Option<Receiver<u8>>,
+    /// Counts the bytes read and written, replayed ones included.
+    traffic: Arc<Traffic>,
+}
+
+impl<S> Replay<S> {
+    pub(crate) fn new(chunks: Vec<Vec<u8>>, inner: S) -> Self {
+        Self {
+            chunks: chunks
+                .into_iter()
+                .filter(|chunk| !chunk.is_empty())
+                .collect(),
+            position: 0,
+            inner,
+            pongs: None,
+            pending: Vec::new(),
+            reserved: None,
+            reserved_opcodes: None,
+            traffic: Arc::default(),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Traffic the bytes read and written are counted in.
+    pub(crate) fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    fn count_received(&self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(len)) = poll {
+            self.traffic
+                .bytes_received
+                .fetch_add(len as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+
+    fn count_sent(&self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(len)) = poll {
+            self.traffic
+                .bytes_sent
+                .fetch_add(len as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+
+    /// Scan the frames written from now on, so their pong frames can be
+    /// dropped with `drop_pongs`. It must be enabled between two frames,
+    /// e.g. right after the handshake.
+    pub(crate) fn filter_pongs(&mut self) {
+        self.pongs.get_or_insert_with(PongFilter::default);
+    }
+
+    /// Drop the frames of reserved opcodes read from now on, instead of
+    /// failing the connection with tungstenite, so it can be served on.
+    /// Their opcodes are received from `take_reserved`. It must be enabled
+    /// between two frames, e.g. right after the handshake.
+    pub(crate) fn filter_reserved(&mut self) {
+        let (sender, receiver) = channel::unbounded();
+        self.reserved = Some((FrameScanner::default(), sender));
+        self.reserved_opcodes = Some(receiver);
+    }
+
+    /// Receiver of the opcodes of the frames dropped by `filter_reserved`,
+    /// the first time it is called.
+    pub(crate) fn take_reserved(&mut self) -> Option<Receiver<u8>> {
+        self.reserved_opcodes.take()
+    }
+
+    /// Drop the pong frames written from now on, or forward them again.
+    pub(crate) fn drop_pongs(&mut self, dropped: bool) {
+        if let Some(pongs) = &mut self.pongs {
+            pongs.dropped = dropped;
+        }
+    }
+}
+
+impl<S: Write + Unpin> Replay<S> {
+    /// Write the pending bytes to `inner`, until they are all written or it
+    /// would block.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, &self.pending);
+            match self.count_sent(poll) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(len)) => {
+                    self.pending.drain(..len);
+                }
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Read + Unpin> Replay<S> {
+    /// Read the replayed bytes, then the ones of `inner`, unfiltered.
+    fn poll_read_unfiltered(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(chunk) = self.chunks.front() {
+            let remaining = &chunk[self.position..];
+            let len = remaining.len().min(buf.len());
+            buf[..len].copy_from_slice(&remaining[..len]);
+            self.position += len;
+            if self.position == chunk.len() {
+                self.chunks.pop_front();
+                self.position = 0;
+            }
+            return self.count_received(Poll::Ready(Ok(len)));
+        }
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count_received(poll)
+    }
+}
+
+impl<S: Read + Unpin> Read for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            let len = match this.poll_read_unfiltered(cx, buf) {
+                Poll::Ready(Ok(len)) if len > 0 => len,
+                other => return other,
+            };
+            let (frames, opcodes) = match &mut this.reserved {
+                Some(reserved) => reserved,
+                None => return Poll::Ready(Ok(len)),
+            };
+            let mut output = Vec::with_capacity(len);
+            frames.scan(&buf[..len], &mut output, |opcode| {
+                let reserved = matches!(opcode, 0x3..=0x7 | 0xB..=0xF);
+                if reserved {
+                    let _ = opcodes.try_send(opcode);
+                }
+                !reserved
+            });
+            // reading nothing would mean the end of the stream
+            if !output.is_empty() {
+                buf[..output.len()].copy_from_slice(&output);
+                return Poll::Ready(Ok(output.len()));
+            }
+        }
+    }
+}
+
+impl<S: Write + Unpin> Write for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match &mut this.pongs {
+            Some(pongs) => {
+                // the bytes are buffered, so failures surface on flush
+                pongs.scan(buf, &mut this.pending);
+                let _ = this.poll_pending(cx);
+                Poll::Ready(Ok(buf.len()))
+            }
+            None => {
+                let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+                this.count_sent(poll)
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_close(cx),
+            other => other,
+        }
+    }
+}
+
+/// Opcode of pong frames.
+const PONG: u8 = 0xA;
+
+/// PongFilter scans a stream of WebSocket frames as they are written, to
+/// drop its pong frames while `dropped` is set.
+#[derive(Debug, Default)]
+struct PongFilter {
+    dropped: bool,
+    frames: FrameScanner,
+}
+
+impl PongFilter {
+    /// Scan `bytes`, appending the ones to forward to `output`.
+    fn scan(&mut self, bytes: &[u8], output: &mut Vec<u8>) {
+        let dropped = self.dropped;
+        self.frames
+            .scan(bytes, output, |opcode| !(dropped && opcode == PONG));
+    }
+}
+
+/// FrameScanner scans a stream of WebSocket frames, whatever the chunks it
+/// is given, to forward or drop each frame depending on its opcode.
+#[derive(Debug, Default)]
+struct FrameScanner {
+    /// Header of the frame being scanned, until it is complete.
+    header: Vec<u8>,
+    /// Payload bytes left in the frame being scanned, and whether they are
+    /// forwarded.
+    payload: Option<(u64, bool)>,
+}
+
+impl FrameScanner {
+    /// Scan `bytes`, appending the ones of the frames whose opcode
+    /// `forward` accepts to `output`.
+    fn scan(
+        &mut self,
+        mut bytes: &[u8],
+        output: &mut Vec<u8>,
+        mut forward: impl FnMut(u8) -> bool,
+    ) {
+        while !bytes.is_empty() {
+            if let Some((remaining, forwarded)) = &mut self.payload {
+                let len = (*remaining).min(bytes.len() as u64) as usize;
+                if *forwarded {
+                    output.extend_from_slice(&bytes[..len]);
+                }
+                *remaining -= len as u64;
+                if *remaining == 0 {
+                    self.payload = None;
+                }
+                bytes = &bytes[len..];
+                continue;
+            }
+
+            self.header.push(bytes[0]);
+            bytes = &bytes[1..];
+            if let Some(payload_len) = self.payload_len() {
+                let forwarded = forward(self.header[0] & 0x0F);
+                if forwarded {
+                    output.append(&mut self.header);
+                }
+                self.header.clear();
+                if payload_len > 0 {
+                    self.payload = Some((payload_len, forwarded));
+                }
+            }
+        }
+    }
+
+    /// Payload length of the frame, once its header is complete.
+    fn payload_len(&self) -> Option<u64> {
+        let header = &self.header;
+        let second = *header.get(1)?;
+        let (len_bytes, short_len) = match second & 0x7F {
+            126 => (2, None),
+            127 => (8, None),
+            len => (0, Some(len as u64)),
+        };
+        let mask_bytes = if second & 0x80 != 0 { 4 } else { 0 };
+        if header.len() < 2 + len_bytes + mask_bytes {
+            return None;
+        }
+        Some(short_len.unwrap_or_else(|| {
+            header[2..2 + len_bytes]
+                .iter()
+                .fold(0, |len, byte| len << 8 | *byte as u64)
+        }))
+    }
+}
+
+/// Memory is an in-memory transport: reading it yields `input` then the end
+/// of the stream, and written bytes are appended to a shared `output`.
+/// It drives the connection logic without a real socket.
+pub(crate) struct Memory {
+    input: Vec<u8>,
+    position: usize,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Memory {
+    pub(crate) fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.to_vec(),
+            position: 0,
+            output: Arc::default(),
+        }
+    }
+
+    /// Handle on the bytes written to the transport.
+    pub(crate) fn output(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.output.clone()
+    }
+}
+
+impl Transport for Memory {
+    fn reset_on_close(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Read for Memory {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let remaining = &self.input[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl Write for Memory {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_pong_frames_only() {
+        let ping = [0x89, 0x01, b'a'];
+        let pong = [0x8A, 0x01, b'a'];
+        let text = [&[0x81, 126, 0x00, 0x80][..], &[b'x'; 128]].concat();
+
+        let mut filter = PongFilter::default();
+        let mut output = vec![];
+        filter.scan(&pong, &mut output);
+        filter.dropped = true;
+        // frames split across writes
+        for bytes in [&pong[..1], &pong[1..], &text[..3], &text[3..], &ping] {
+            filter.scan(bytes, &mut output);
+        }
+
+        assert_eq!(output, [&pong[..], &text, &ping].concat());
+    }
+}
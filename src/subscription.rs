@@ -0,0 +1,111 @@
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+
+/// Subscription streams of a connection.
+/// Active subscriptions are streamed in turn, one message at a time, so
+/// their messages are interleaved.
+pub(crate) struct Subscriptions<'a> {
+    streams: &'a [(String, Vec<Value>)],
+    active: Vec<String>,
+    pending: VecDeque<(&'a str, std::slice::Iter<'a, Value>)>,
+}
+
+impl<'a> Subscriptions<'a> {
+    pub(crate) fn new(streams: &'a [(String, Vec<Value>)]) -> Self {
+        Self {
+            streams,
+            active: vec![],
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Handle `{"subscribe": id}` and `{"unsubscribe": id}` messages.
+    /// Returns whether `message` was one of them for a known subscription.
+    pub(crate) fn control(&mut self, message: &Value) -> bool {
+        if let Some(id) = message.get("subscribe").and_then(Value::as_str) {
+            let (id, responses) = match self.streams.iter().find(|(stream, _)| stream == id) {
+                Some((id, responses)) => (id, responses),
+                None => return false,
+            };
+            if !self.active.contains(id) {
+                self.active.push(id.clone());
+                self.pending.push_back((id, responses.iter()));
+            }
+            return true;
+        }
+        if let Some(id) = message.get("unsubscribe").and_then(Value::as_str) {
+            if !self.streams.iter().any(|(stream, _)| stream == id) {
+                return false;
+            }
+            self.active.retain(|active| active != id);
+            self.pending.retain(|(pending, _)| *pending != id);
+            return true;
+        }
+        false
+    }
+
+    /// Whether some active subscription has messages left to stream.
+    pub(crate) fn is_streaming(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Next message to stream, tagged with its subscription id.
+    pub(crate) fn next(&mut self) -> Option<Value> {
+        while let Some((id, mut responses)) = self.pending.pop_front() {
+            if let Some(response) = responses.next() {
+                if responses.len() > 0 {
+                    self.pending.push_back((id, responses));
+                }
+                return Some(json!({"subscription": id, "data": response}));
+            }
+        }
+        None
+    }
+
+    /// Ids of the subscriptions the client subscribed to, in order.
+    pub(crate) fn active(&self) -> &[String] {
+        &self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_active_subscriptions() {
+        let streams = vec![
+            ("a".to_string(), vec![json!(1), json!(2)]),
+            ("b".to_string(), vec![json!(3)]),
+        ];
+        let mut subscriptions = Subscriptions::new(&streams);
+        assert!(subscriptions.control(&json!({"subscribe": "a"})));
+        assert!(subscriptions.control(&json!({"subscribe": "b"})));
+        assert!(!subscriptions.control(&json!({"subscribe": "unknown"})));
+
+        let mut streamed = vec![];
+        while subscriptions.is_streaming() {
+            streamed.extend(subscriptions.next());
+        }
+        assert_eq!(
+            streamed,
+            vec![
+                json!({"subscription": "a", "data": 1}),
+                json!({"subscription": "b", "data": 3}),
+                json!({"subscription": "a", "data": 2}),
+            ]
+        );
+        assert_eq!(subscriptions.active(), ["a", "b"]);
+    }
+
+    #[test]
+    fn stop_streaming_unsubscribed() {
+        let streams = vec![("a".to_string(), vec![json!(1), json!(2)])];
+        let mut subscriptions = Subscriptions::new(&streams);
+        subscriptions.control(&json!({"subscribe": "a"}));
+        assert!(subscriptions.control(&json!({"unsubscribe": "a"})));
+
+        assert!(!subscriptions.is_streaming());
+        assert!(subscriptions.active().is_empty());
+    }
+}